@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use threadpool::ThreadPool;
+use num_cpus;
+
+use beat::{BeatDetector, SimpleBeatDetector};
+use chroma::ChromaAnalyzer;
+use features::FeatureExtractor;
+use frame::SpectrumFrame;
+use gst::Samples;
+use loudness::LoudnessMeter;
+use pitch::PitchDetector;
+use process;
+use simple_decoder;
+
+// bounds of the exponent of the largest fft, see `window_range`
+pub const MIN_WINDOW: usize = 11;
+pub const MAX_WINDOW: usize = 16;
+pub const DEFAULT_WINDOW: usize = 13;
+// share of the running maximum kept per frame by the automatic gain control
+pub const AGC_DECAY: f32 = 0.99;
+
+// the six fft sizes 2^k merged into the seven octaves, the largest one is 2^window
+pub fn window_range(window: usize) -> Range<usize> {
+    window - 5..window + 1
+}
+
+fn normalize(input: Vec<f32>, global_max: f32, decay: f32) -> (Vec<f32>, f32) {
+    let max = input.iter().cloned().fold(0.0, f32::max);
+
+    let mut global_max = global_max * decay;
+    if global_max < max {
+        global_max = max;
+    }
+    // nothing but silence so far
+    if global_max <= 0.0 {
+        return (vec![0.0; input.len()], 0.0);
+    }
+    let out: Vec<f32> = input.iter()
+    .map(|v| v / global_max)
+   // .map(|v| v.log(10.0) / 2.5 + 1.0)
+   // .map(|v| if v < 0.0 { 0.0 } else { v })
+    .collect();
+
+    (out, global_max)
+
+}
+
+// runs one fft per window size 2^k and merges their results into a single spectrum
+pub struct Analyzer {
+    sample_rate: usize,
+    range: Range<usize>,
+    pool: ThreadPool,
+    processors: Vec<(usize, Arc<Mutex<process::Processor>>)>,
+    beat_detector: SimpleBeatDetector,
+    pitch_detector: PitchDetector,
+    chroma_analyzer: ChromaAnalyzer,
+    feature_extractor: FeatureExtractor,
+    loudness_meter: LoudnessMeter,
+    // cache the last result of an fft
+    // this enables us to to updates even if just one fft reported
+    // new values
+    fft_cache: HashMap<usize, Vec<f32>>,
+    global_max: f32,
+    // without the automatic gain control the gain is held at its last value
+    agc: bool,
+    agc_decay: f32,
+    sequence: u64,
+}
+
+fn processors(range: Range<usize>, sample_rate: usize) -> Vec<(usize, Arc<Mutex<process::Processor>>)> {
+    range
+        .map(|k| {
+            (
+                k,
+                Arc::new(Mutex::new(process::Processor::new(k, sample_rate))),
+            )
+        })
+        .collect()
+}
+
+impl Analyzer {
+    pub fn new(range: Range<usize>, sample_rate: usize) -> Self {
+        // create a thread pool to execute everything on
+        let pool = ThreadPool::new(usize::max(num_cpus::get_physical() - 1, 1));
+
+        Analyzer {
+            sample_rate: sample_rate,
+            range: range.clone(),
+            pool: pool,
+            // create all the fft processors
+            processors: processors(range, sample_rate),
+            beat_detector: SimpleBeatDetector::new(sample_rate),
+            pitch_detector: PitchDetector::new(2usize.pow(13), sample_rate),
+            chroma_analyzer: ChromaAnalyzer::new(),
+            feature_extractor: FeatureExtractor::new(2usize.pow(11), sample_rate),
+            loudness_meter: LoudnessMeter::new(sample_rate),
+            fft_cache: HashMap::new(),
+            global_max: 0.0,
+            agc: true,
+            agc_decay: AGC_DECAY,
+            sequence: 0,
+        }
+    }
+
+    // switch to other fft sizes, the rest of the analysis keeps its state
+    pub fn set_range(&mut self, range: Range<usize>) {
+        if range == self.range {
+            return;
+        }
+        self.processors = processors(range.clone(), self.sample_rate);
+        self.fft_cache.clear();
+        self.range = range;
+    }
+
+    pub fn set_agc(&mut self, enabled: bool) {
+        self.agc = enabled;
+    }
+
+    pub fn set_agc_decay(&mut self, decay: f32) {
+        self.agc_decay = decay;
+    }
+
+    pub fn analyze(&mut self, samples: Samples) -> SpectrumFrame {
+        let (tx, rx) = channel();
+        let d = samples.data;
+
+        let rms = (d.iter().map(|v| v * v).sum::<f32>() / usize::max(d.len(), 1) as f32).sqrt();
+        let peak = d.iter().map(|v| v.abs()).fold(0.0, f32::max);
+        let beat = self.beat_detector.analyze(&d);
+        let pitch = self.pitch_detector.analyze(&d);
+        let features = self.feature_extractor.analyze(&d);
+        let loudness = match samples.stereo {
+            Some(ref stereo) => self.loudness_meter.process(&[&stereo.left, &stereo.right]),
+            None => self.loudness_meter.process(&[&d]),
+        };
+
+        // feed it into our fft processs loop
+        self.processors
+            .iter()
+            .map(|&(k, ref p)| (k, Arc::clone(p)))
+            .map(|(k, p)| {
+                let d = d.clone();
+                let tx = tx.clone();
+                self.pool.execute(move || {
+                    let mut p = p.lock().expect("Processor scheduled more than once");
+                    tx.send((k, p.process(d))).expect(
+                        "Result channel must be open",
+                    );
+                });
+            })
+            .last();
+
+        // await all the ffts before continuing
+        // check if all the ffts did return results, if not pick the previous result of that
+        // fft (if available)
+        let mut bins = vec![0.0; 7 * 12];
+        let mut fft_sizes = vec![];
+        let mut results: Vec<(usize, Vec<f32>)> = vec![];
+        for (k, r) in rx.into_iter().take(self.processors.len()) {
+            let r = match r {
+                Some(r) => {
+                    fft_sizes.push(2usize.pow(k as u32));
+                    self.fft_cache.insert(k, r.clone());
+                    r
+                }
+                None => self.fft_cache.get(&k).cloned().unwrap_or_else(|| vec![0.0; 7 * 12]),
+            };
+            results.push((k, r));
+        }
+        fft_sizes.sort();
+
+        // k \in [8, 13] = range
+        // every fft contributes the octave it resolves best, larger windows the lower ones
+        let range_end = self.range.end;
+        let no_of_points = bins.len();
+        for (k, r) in results {
+            debug_assert!(no_of_points == r.len());
+            let to = (range_end + 1 - k) * 12;
+            let from = if k == range_end - 1 {
+                0
+            } else {
+                (range_end - k) * 12
+            };
+            bins.splice(from..to, r.into_iter().skip(from).take(to - from));
+        }
+
+        let (merged_bins, max) = if self.agc || self.global_max <= 0.0 {
+            normalize(bins, self.global_max, self.agc_decay)
+        } else {
+            let global_max = self.global_max;
+            (bins.into_iter().map(|v| v / global_max).collect(), global_max)
+        };
+        self.global_max = max;
+
+        let (chroma, key, chord) = self.chroma_analyzer.analyze(&merged_bins);
+
+        let sequence = self.sequence;
+        self.sequence += 1;
+
+        SpectrumFrame {
+            sequence: sequence,
+            pts: samples.pts,
+            captured: samples.captured,
+            sample_rate: self.sample_rate,
+            fft_sizes: fft_sizes,
+            freqs: (0..merged_bins.len())
+                .map(|v| simple_decoder::semitone_freq(v) as f32)
+                .collect(),
+            bins: merged_bins,
+            gain: if max > 0.0 { 1.0 / max } else { 1.0 },
+            rms: rms,
+            peak: peak,
+            beat: beat,
+            pitch: pitch,
+            chroma: chroma,
+            key: key,
+            chord: chord,
+            features: features,
+            loudness: loudness,
+            samples: d,
+            stereo: samples.stereo,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Analyzer;
+    use gst::Samples;
+    use std::f32::consts::PI;
+    use std::time::Instant;
+
+    fn sine(amplitude: f32) -> Samples {
+        Samples {
+            pts: None,
+            captured: Instant::now(),
+            data: (0..8192)
+                .map(|i| amplitude * (2.0 * PI * 440.0 * i as f32 / 44100.0).sin())
+                .collect(),
+            stereo: None,
+        }
+    }
+
+    fn max(bins: &[f32]) -> f32 {
+        bins.iter().cloned().fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_merges_every_fft_into_one_spectrum() {
+        let mut analyzer = Analyzer::new(8..14, 44100);
+        let first = analyzer.analyze(sine(1.0));
+        assert_eq!(first.sequence, 0);
+        assert_eq!(first.bins.len(), 7 * 12);
+        assert_eq!(first.freqs.len(), 7 * 12);
+        assert_eq!(first.fft_sizes, vec![256, 512, 1024, 2048, 4096, 8192]);
+        assert_eq!(max(&first.bins), 1.0);
+
+        let second = analyzer.analyze(sine(1.0));
+        assert_eq!(second.sequence, 1);
+    }
+
+    #[test]
+    fn test_silence_gives_zero_bins() {
+        let mut analyzer = Analyzer::new(8..14, 44100);
+        let silent = analyzer.analyze(sine(0.0));
+        assert!(silent.bins.iter().all(|&v| v == 0.0));
+        assert!(silent.chroma.iter().all(|&v| v == 0.0));
+
+        let frame = analyzer.analyze(sine(1.0));
+        assert_eq!(max(&frame.bins), 1.0);
+    }
+
+    #[test]
+    fn test_normalization_follows_the_agc() {
+        // the running maximum halves every frame and catches up with the quieter input
+        let mut analyzer = Analyzer::new(8..14, 44100);
+        analyzer.set_agc_decay(0.5);
+        analyzer.analyze(sine(1.0));
+        let frame = analyzer.analyze(sine(0.5));
+        assert!((max(&frame.bins) - 1.0).abs() < 1e-3);
+
+        // without the agc the gain of the louder frame is held
+        let mut analyzer = Analyzer::new(8..14, 44100);
+        let loud = analyzer.analyze(sine(1.0));
+        analyzer.set_agc(false);
+        let frame = analyzer.analyze(sine(0.5));
+        assert!((max(&frame.bins) - 0.5).abs() < 1e-3);
+        assert_eq!(frame.gain, loud.gain);
+    }
+}
@@ -0,0 +1,18 @@
+// group the semitone bins into `n` bands of (roughly) equal width by averaging them
+pub fn group(bins: &[f32], n: usize) -> Vec<f32> {
+    if n == 0 || bins.is_empty() {
+        return vec![];
+    }
+    if n >= bins.len() {
+        return bins.to_vec();
+    }
+
+    (0..n)
+        .map(|band| {
+            let from = band * bins.len() / n;
+            let to = (band + 1) * bins.len() / n;
+            let width = usize::max(to - from, 1);
+            bins[from..to].iter().sum::<f32>() / width as f32
+        })
+        .collect()
+}
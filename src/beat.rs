@@ -1,3 +1,4 @@
+use std::time::Instant;
 
 pub trait BeatDetector {
     // feed samples into the detector and returns if a beat was detected within thos samples
@@ -75,3 +76,60 @@ impl BeatDetector for SimpleBeatDetector {
         }
     }
 }
+
+// derives a tempo from the intervals between detected beats
+pub struct TempoEstimator {
+    last_beat: Option<Instant>,
+    intervals: Vec<f32>,
+}
+
+// beats closer together than this are considered the same beat (200 bpm)
+const MIN_BEAT_INTERVAL: f32 = 0.3;
+// pauses longer than this restart the estimation (40 bpm)
+const MAX_BEAT_INTERVAL: f32 = 1.5;
+// number of intervals the tempo is derived from
+const MAX_INTERVALS: usize = 16;
+
+impl TempoEstimator {
+    pub fn new() -> Self {
+        TempoEstimator {
+            last_beat: None,
+            intervals: Vec::with_capacity(MAX_INTERVALS),
+        }
+    }
+
+    // register a beat that happened at `at`, returns false if it was discarded as a duplicate
+    pub fn beat(&mut self, at: Instant) -> bool {
+        if let Some(last) = self.last_beat {
+            let interval = if at > last {
+                let d = at - last;
+                d.as_secs() as f32 + d.subsec_nanos() as f32 / 1000000000.0
+            } else {
+                0.0
+            };
+            if interval < MIN_BEAT_INTERVAL {
+                return false;
+            }
+            if interval > MAX_BEAT_INTERVAL {
+                self.intervals.clear();
+            } else {
+                if self.intervals.len() == MAX_INTERVALS {
+                    self.intervals.remove(0);
+                }
+                self.intervals.push(interval);
+            }
+        }
+        self.last_beat = Some(at);
+        true
+    }
+
+    // the median of the recent beat intervals in beats per minute
+    pub fn bpm(&self) -> Option<f32> {
+        if self.intervals.len() < 4 {
+            return None;
+        }
+        let mut sorted = self.intervals.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        Some(60.0 / sorted[sorted.len() / 2])
+    }
+}
@@ -0,0 +1,437 @@
+// A bounded "latest value wins" broadcast channel.
+//
+// Every subscriber owns a single slot. Sending overwrites whatever is still waiting in a
+// slot, so a slow sink never builds up a queue of stale frames. Instead the overwritten
+// values are counted as dropped which allows us to report which sinks can't keep up. Values
+// that must not get lost with them, like beats, can be merged into the value replacing them.
+//
+// Values carry the instant their audio was captured. This is used to measure the latency
+// of each sink and to hold values back for sinks that have to be delayed to line up with
+// the audio (e.g. when it is played through a delayed PA system).
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvError, TryRecvError};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// upper bound of values waiting in a delayed slot
+const MAX_DELAYED: usize = 1024;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Stats {
+    pub delivered: u64,
+    pub dropped: u64,
+    // sum of the capture to delivery latency of all delivered values
+    pub latency_total: Duration,
+}
+
+impl Stats {
+    pub fn avg_latency(&self) -> Duration {
+        if self.delivered == 0 {
+            Duration::new(0, 0)
+        } else {
+            self.latency_total / self.delivered as u32
+        }
+    }
+
+    // ratio of frames that never reached the sink
+    pub fn drop_ratio(&self) -> f32 {
+        let total = self.delivered + self.dropped;
+        if total == 0 {
+            0.0
+        } else {
+            self.dropped as f32 / total as f32
+        }
+    }
+}
+
+struct State<T> {
+    queue: VecDeque<(Instant, T)>,
+    stats: Stats,
+    closed: bool,
+}
+
+impl<T> State<T> {
+    fn deliver(&mut self, origin: Instant, value: T) -> T {
+        self.stats.delivered += 1;
+        self.stats.latency_total += origin.elapsed();
+        value
+    }
+
+    // take the latest value that is due, all older ones are skipped
+    fn take_due(&mut self, delay: Duration, merge: Option<Merge<T>>) -> Option<T> {
+        let mut due: Option<(Instant, T)> = None;
+        while self.queue
+            .front()
+            .map(|&(origin, _)| origin.elapsed() >= delay)
+            .unwrap_or(false)
+        {
+            let mut next = self.queue.pop_front().unwrap();
+            if let Some((_, skipped)) = due {
+                self.stats.dropped += 1;
+                if let Some(merge) = merge {
+                    merge(&skipped, &mut next.1);
+                }
+            }
+            due = Some(next);
+        }
+        due.map(|(origin, value)| self.deliver(origin, value))
+    }
+
+    // time until the next value becomes due
+    fn wait_time(&self, delay: Duration) -> Option<Duration> {
+        self.queue.front().map(|&(origin, _)| {
+            let age = origin.elapsed();
+            if age >= delay {
+                Duration::new(0, 0)
+            } else {
+                delay - age
+            }
+        })
+    }
+}
+
+// called with a value that is skipped and the newer value that is handed out instead
+pub type Merge<T> = fn(&T, &mut T);
+
+struct Slot<T> {
+    name: String,
+    delay: Duration,
+    merge: Option<Merge<T>>,
+    state: Mutex<State<T>>,
+    cond: Condvar,
+}
+
+struct Shared<T> {
+    slots: Mutex<Vec<Arc<Slot<T>>>>,
+    senders: AtomicUsize,
+    merge: Option<Merge<T>>,
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+// read only handle on the statistics that doesn't keep the channel open
+pub struct Monitor<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    slot: Arc<Slot<T>>,
+    interval: Option<Duration>,
+    last_recv: Option<Instant>,
+}
+
+pub fn channel<T: Clone>() -> Sender<T> {
+    new_channel(None)
+}
+
+// like `channel` but every skipped value is merged into the one that replaces it
+pub fn channel_merging<T: Clone>(merge: Merge<T>) -> Sender<T> {
+    new_channel(Some(merge))
+}
+
+fn new_channel<T: Clone>(merge: Option<Merge<T>>) -> Sender<T> {
+    Sender {
+        shared: Arc::new(Shared {
+            slots: Mutex::new(Vec::new()),
+            senders: AtomicUsize::new(1),
+            merge: merge,
+        }),
+    }
+}
+
+impl<T: Clone> Sender<T> {
+    // register a new sink, `fps` limits how often the receiver hands out values
+    pub fn subscribe(&self, name: &str, fps: Option<u32>) -> Receiver<T> {
+        self.subscribe_delayed(name, fps, Duration::new(0, 0))
+    }
+
+    // like `subscribe` but values are only handed out once they are `delay` old
+    pub fn subscribe_delayed(&self, name: &str, fps: Option<u32>, delay: Duration) -> Receiver<T> {
+        let slot = Arc::new(Slot {
+            name: name.to_string(),
+            delay: delay,
+            merge: self.shared.merge,
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                stats: Stats::default(),
+                closed: false,
+            }),
+            cond: Condvar::new(),
+        });
+        self.shared.slots.lock().unwrap().push(Arc::clone(&slot));
+        Receiver {
+            slot: slot,
+            interval: fps.map(|fps| Duration::from_secs(1) / fps),
+            last_recv: None,
+        }
+    }
+
+    pub fn send(&self, value: T) {
+        self.send_at(Instant::now(), value)
+    }
+
+    // send a value whose audio was captured at `origin`
+    pub fn send_at(&self, origin: Instant, value: T) {
+        let mut slots = self.shared.slots.lock().unwrap();
+        // forget about receivers that have been dropped
+        slots.retain(|slot| Arc::strong_count(slot) > 1);
+        for slot in slots.iter() {
+            let mut state = slot.state.lock().unwrap();
+            // undelayed sinks only ever need the latest value
+            let capacity = if slot.delay == Duration::new(0, 0) {
+                1
+            } else {
+                MAX_DELAYED
+            };
+            let mut value = value.clone();
+            while state.queue.len() >= capacity {
+                let (_, skipped) = state.queue.pop_front().unwrap();
+                state.stats.dropped += 1;
+                if let Some(merge) = slot.merge {
+                    merge(&skipped, &mut value);
+                }
+            }
+            state.queue.push_back((origin, value));
+            slot.cond.notify_one();
+        }
+    }
+
+    pub fn monitor(&self) -> Monitor<T> {
+        Monitor { shared: Arc::clone(&self.shared) }
+    }
+}
+
+impl<T> Monitor<T> {
+    // per sink statistics, used to report backpressure
+    pub fn stats(&self) -> Vec<(String, Stats)> {
+        self.shared
+            .slots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|slot| (slot.name.clone(), slot.state.lock().unwrap().stats))
+            .collect()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        Sender { shared: Arc::clone(&self.shared) }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // only the last sender closes the channel
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) > 1 {
+            return;
+        }
+        for slot in self.shared.slots.lock().unwrap().iter() {
+            slot.state.lock().unwrap().closed = true;
+            slot.cond.notify_all();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    // block until a new value is available, honoring the configured frame rate
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        if let (Some(interval), Some(last)) = (self.interval, self.last_recv) {
+            let elapsed = last.elapsed();
+            if elapsed < interval {
+                sleep(interval - elapsed);
+            }
+        }
+
+        let delay = self.slot.delay;
+        let mut state = self.slot.state.lock().unwrap();
+        loop {
+            if let Some(value) = state.take_due(delay, self.slot.merge) {
+                self.last_recv = Some(Instant::now());
+                return Ok(value);
+            }
+            if state.closed {
+                return Err(RecvError);
+            }
+            state = match state.wait_time(delay) {
+                Some(timeout) => self.slot.cond.wait_timeout(state, timeout).unwrap().0,
+                None => self.slot.cond.wait(state).unwrap(),
+            };
+        }
+    }
+
+    // like `recv` but returns right away, before the frame rate allows the next value too
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let (Some(interval), Some(last)) = (self.interval, self.last_recv) {
+            if last.elapsed() < interval {
+                return Err(TryRecvError::Empty);
+            }
+        }
+
+        let mut state = self.slot.state.lock().unwrap();
+        match state.take_due(self.slot.delay, self.slot.merge) {
+            Some(value) => {
+                self.last_recv = Some(Instant::now());
+                Ok(value)
+            }
+            None if state.closed && state.queue.is_empty() => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.slot.state.lock().unwrap().stats
+    }
+}
+
+// print the latency and dropped frame counters of all sinks every `interval`
+pub fn report<T>(monitor: Monitor<T>, interval: Duration) {
+    let mut previous: Vec<(String, Stats)> = Vec::new();
+    loop {
+        sleep(interval);
+        let current = monitor.stats();
+        for &(ref name, ref stats) in current.iter() {
+            let before = previous
+                .iter()
+                .find(|&&(ref n, _)| n == name)
+                .map(|&(_, s)| s)
+                .unwrap_or_default();
+            // a sink may have been replaced by a new one of the same name since
+            let window = Stats {
+                delivered: stats.delivered.saturating_sub(before.delivered),
+                dropped: stats.dropped.saturating_sub(before.dropped),
+                latency_total: stats
+                    .latency_total
+                    .checked_sub(before.latency_total)
+                    .unwrap_or_default(),
+            };
+            let latency = window.avg_latency();
+            println!(
+                "sink {}: latency {:.1}ms, {} frames delivered",
+                name,
+                latency.as_secs() as f64 * 1000.0 + latency.subsec_nanos() as f64 / 1000000.0,
+                window.delivered
+            );
+            if window.dropped > 0 {
+                println!(
+                    "sink {} is falling behind: dropped {} of {} frames ({:.1}%)",
+                    name,
+                    window.dropped,
+                    window.delivered + window.dropped,
+                    window.drop_ratio() * 100.0
+                );
+            }
+        }
+        previous = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel, channel_merging, State, Stats};
+    use std::collections::VecDeque;
+    use std::sync::mpsc::{RecvError, TryRecvError};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_latest_value_wins() {
+        let tx = channel();
+        let mut rx = tx.subscribe("sink", None);
+        tx.send(1);
+        tx.send(2);
+        tx.send(3);
+        assert_eq!(rx.recv(), Ok(3));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        let stats = rx.stats();
+        assert_eq!(stats.delivered, 1);
+        assert_eq!(stats.dropped, 2);
+    }
+
+    #[test]
+    fn test_skipped_values_are_merged() {
+        fn latch(skipped: &(u32, bool), next: &mut (u32, bool)) {
+            next.1 |= skipped.1;
+        }
+
+        let tx = channel_merging(latch);
+        let mut rx = tx.subscribe("sink", None);
+        let mut delayed = tx.subscribe_delayed("delayed", None, Duration::from_millis(10));
+        tx.send((1, true));
+        tx.send((2, false));
+        tx.send((3, false));
+        assert_eq!(rx.recv(), Ok((3, true)));
+        tx.send((4, false));
+        assert_eq!(rx.recv(), Ok((4, false)));
+        sleep(Duration::from_millis(20));
+        assert_eq!(delayed.recv(), Ok((4, true)));
+    }
+
+    #[test]
+    fn test_frame_rate_interval() {
+        let tx = channel();
+        // one value every 50ms
+        let mut rx = tx.subscribe("sink", Some(20));
+        tx.send(1);
+        assert_eq!(rx.try_recv(), Ok(1));
+
+        tx.send(2);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        let start = Instant::now();
+        assert_eq!(rx.recv(), Ok(2));
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_take_due_skips_older_values() {
+        let now = Instant::now();
+        let mut queue = VecDeque::new();
+        queue.push_back((now - Duration::from_millis(300), 1));
+        queue.push_back((now - Duration::from_millis(200), 2));
+        queue.push_back((now + Duration::from_secs(60), 3));
+        let mut state = State {
+            queue: queue,
+            stats: Stats::default(),
+            closed: false,
+        };
+
+        assert_eq!(state.take_due(Duration::from_millis(100), None), Some(2));
+        assert_eq!(state.stats.delivered, 1);
+        assert_eq!(state.stats.dropped, 1);
+        assert_eq!(state.take_due(Duration::from_millis(100), None), None);
+        assert_eq!(state.queue.len(), 1);
+    }
+
+    #[test]
+    fn test_delayed_delivery() {
+        let tx = channel();
+        let mut rx = tx.subscribe_delayed("sink", None, Duration::from_millis(50));
+        tx.send(1);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        sleep(Duration::from_millis(60));
+        assert_eq!(rx.try_recv(), Ok(1));
+    }
+
+    #[test]
+    fn test_closes_after_last_sender() {
+        let tx = channel();
+        let mut rx = tx.subscribe("sink", None);
+        let other = tx.clone();
+        drop(other);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        tx.send(1);
+        drop(tx);
+        // values sent before are still handed out
+        assert_eq!(rx.recv(), Ok(1));
+        assert_eq!(rx.recv(), Err(RecvError));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+}
@@ -0,0 +1,289 @@
+// Folds the semitone bins into a 12 dimensional chroma vector (C, C#, ..., B) and derives
+// the key of the song as well as the chord that is currently playing from it.
+//
+// Every bin does not only count towards its own pitch class but, with decreasing weight,
+// also towards the pitch classes of the notes it could be a harmonic of. The key is found by
+// correlating a long running average of the chroma with the Krumhansl-Kessler profiles, the
+// chord by matching a short running average against triad templates.
+
+use simple_decoder::PER_OCTAVE;
+
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+// the first semitone bin is an A
+const FIRST_BIN_PITCH_CLASS: usize = 9;
+
+// semitone distance of the 1st, 3rd and 5th harmonic from the fundamental, the octaves
+// (2nd and 4th harmonic) fall into the pitch class of the fundamental and are left out
+const HARMONIC_OFFSETS: [usize; 3] = [0, 19, 28];
+const HARMONIC_DECAY: f32 = 0.6;
+
+// per frame smoothing factors of the running chroma averages
+const KEY_SMOOTHING: f32 = 0.98;
+const CHORD_SMOOTHING: f32 = 0.6;
+
+// minimum correlation/similarity to report a key or chord at all
+const MIN_KEY_CORRELATION: f32 = 0.5;
+const MIN_CHORD_SIMILARITY: f32 = 0.75;
+
+const MAJOR_PROFILE: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Key {
+    // pitch class of the tonic, 0 is C
+    pub tonic: u8,
+    pub mode: Mode,
+    // correlation with the key profile, up to 1
+    pub confidence: f32,
+}
+
+impl Key {
+    // e.g. "A minor"
+    pub fn name(&self) -> String {
+        let mode = match self.mode {
+            Mode::Major => "major",
+            Mode::Minor => "minor",
+        };
+        format!("{} {}", NOTE_NAMES[self.tonic as usize], mode)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Chord {
+    pub root: u8,
+    pub mode: Mode,
+    // cosine similarity with the chord template, up to 1
+    pub confidence: f32,
+}
+
+impl Chord {
+    // e.g. "Am" or "C"
+    pub fn name(&self) -> String {
+        let suffix = match self.mode {
+            Mode::Major => "",
+            Mode::Minor => "m",
+        };
+        format!("{}{}", NOTE_NAMES[self.root as usize], suffix)
+    }
+}
+
+pub fn chroma(bins: &[f32]) -> Vec<f32> {
+    let mut chroma = vec![0.0; PER_OCTAVE];
+    for (v, &energy) in bins.iter().enumerate() {
+        let mut weight = 1.0;
+        for &offset in HARMONIC_OFFSETS.iter() {
+            if offset > v {
+                break;
+            }
+            let pitch_class = (v - offset + FIRST_BIN_PITCH_CLASS) % PER_OCTAVE;
+            chroma[pitch_class] += energy * weight;
+            weight *= HARMONIC_DECAY;
+        }
+    }
+
+    let max = chroma.iter().cloned().fold(0.0, f32::max);
+    if max > 0.0 {
+        for v in chroma.iter_mut() {
+            *v /= max;
+        }
+    }
+    chroma
+}
+
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b.iter()) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a).powi(2);
+        var_b += (y - mean_b).powi(2);
+    }
+    if var_a == 0.0 || var_b == 0.0 {
+        0.0
+    } else {
+        cov / (var_a * var_b).sqrt()
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+// the profile transposed to start at `tonic`
+fn rotate(profile: &[f32], tonic: usize) -> Vec<f32> {
+    (0..PER_OCTAVE)
+        .map(|pc| profile[(pc + PER_OCTAVE - tonic) % PER_OCTAVE])
+        .collect()
+}
+
+fn triad(root: usize, mode: Mode) -> Vec<f32> {
+    let third = match mode {
+        Mode::Major => 4,
+        Mode::Minor => 3,
+    };
+    let mut template = vec![0.0; PER_OCTAVE];
+    for &interval in [0, third, 7].iter() {
+        template[(root + interval) % PER_OCTAVE] = 1.0;
+    }
+    template
+}
+
+fn smooth(average: &mut Vec<f32>, chroma: &[f32], factor: f32) {
+    average.resize(chroma.len(), 0.0);
+    for (a, c) in average.iter_mut().zip(chroma.iter()) {
+        *a = *a * factor + c * (1.0 - factor);
+    }
+}
+
+pub struct ChromaAnalyzer {
+    key_average: Vec<f32>,
+    chord_average: Vec<f32>,
+}
+
+impl ChromaAnalyzer {
+    pub fn new() -> Self {
+        ChromaAnalyzer {
+            key_average: vec![0.0; PER_OCTAVE],
+            chord_average: vec![0.0; PER_OCTAVE],
+        }
+    }
+
+    pub fn analyze(&mut self, bins: &[f32]) -> (Vec<f32>, Option<Key>, Option<Chord>) {
+        // a single invalid bin would stay in the averages for good
+        let bins: Vec<f32> = bins
+            .iter()
+            .map(|&v| if v.is_finite() { v } else { 0.0 })
+            .collect();
+        let chroma = chroma(&bins);
+        smooth(&mut self.key_average, &chroma, KEY_SMOOTHING);
+        smooth(&mut self.chord_average, &chroma, CHORD_SMOOTHING);
+        let key = self.key();
+        let chord = self.chord();
+        (chroma, key, chord)
+    }
+
+    fn key(&self) -> Option<Key> {
+        let mut best: Option<Key> = None;
+        for tonic in 0..PER_OCTAVE {
+            for &(mode, profile) in [(Mode::Major, &MAJOR_PROFILE), (Mode::Minor, &MINOR_PROFILE)]
+                .iter()
+            {
+                let r = correlation(&self.key_average, &rotate(profile, tonic));
+                if best.as_ref().map(|k| r > k.confidence).unwrap_or(true) {
+                    best = Some(Key {
+                        tonic: tonic as u8,
+                        mode: mode,
+                        confidence: r,
+                    });
+                }
+            }
+        }
+        best.and_then(|k| if k.confidence >= MIN_KEY_CORRELATION {
+            Some(k)
+        } else {
+            None
+        })
+    }
+
+    fn chord(&self) -> Option<Chord> {
+        let mut best: Option<Chord> = None;
+        for root in 0..PER_OCTAVE {
+            for &mode in [Mode::Major, Mode::Minor].iter() {
+                let s = cosine_similarity(&self.chord_average, &triad(root, mode));
+                if best.as_ref().map(|c| s > c.confidence).unwrap_or(true) {
+                    best = Some(Chord {
+                        root: root as u8,
+                        mode: mode,
+                        confidence: s,
+                    });
+                }
+            }
+        }
+        best.and_then(|c| if c.confidence >= MIN_CHORD_SIMILARITY {
+            Some(c)
+        } else {
+            None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chroma, ChromaAnalyzer, Mode};
+
+    // C4, E4 and G4, the first bin is A1
+    fn c_major_triad() -> Vec<f32> {
+        let mut bins = vec![0.0; 84];
+        for &v in [39, 43, 46].iter() {
+            bins[v] = 1.0;
+        }
+        bins
+    }
+
+    #[test]
+    fn test_chroma_of_notes() {
+        // C2 is too low to be a harmonic of anything
+        let mut bins = vec![0.0; 84];
+        bins[3] = 0.5;
+        let mut expected = vec![0.0; 12];
+        expected[0] = 1.0;
+        assert_eq!(chroma(&bins), expected);
+
+        // the notes of the triad are the strongest pitch classes
+        let chroma = chroma(&c_major_triad());
+        let mut classes: Vec<usize> = (0..12).collect();
+        classes.sort_by(|&a, &b| chroma[b].partial_cmp(&chroma[a]).unwrap());
+        classes.truncate(3);
+        classes.sort();
+        assert_eq!(classes, vec![0, 4, 7]);
+    }
+
+    #[test]
+    fn test_c_major_triad() {
+        let (_, key, chord) = ChromaAnalyzer::new().analyze(&c_major_triad());
+
+        let chord = chord.unwrap();
+        assert_eq!(chord.name(), "C");
+        let key = key.unwrap();
+        assert_eq!(key.tonic, 0);
+        assert_eq!(key.mode, Mode::Major);
+        assert_eq!(key.name(), "C major");
+    }
+
+    #[test]
+    fn test_recovers_from_silence() {
+        let mut analyzer = ChromaAnalyzer::new();
+        let (chroma, key, chord) = analyzer.analyze(&vec![0.0; 84]);
+        assert_eq!(chroma, vec![0.0; 12]);
+        assert!(key.is_none() && chord.is_none());
+
+        let mut invalid = c_major_triad();
+        invalid[10] = ::std::f32::NAN;
+        analyzer.analyze(&invalid);
+
+        let (_, key, _) = analyzer.analyze(&c_major_triad());
+        assert_eq!(key.unwrap().name(), "C major");
+    }
+}
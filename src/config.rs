@@ -0,0 +1,319 @@
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+use failure::Error;
+
+use lightsd;
+use visual;
+
+#[derive(Debug, Fail)]
+pub enum ConfigError {
+    #[fail(display = "unknown option: {}", _0)]
+    UnknownOption(String),
+    #[fail(display = "option {} requires a value", _0)]
+    MissingValue(String),
+    #[fail(display = "invalid value {:?} for option {}", _1, _0)]
+    InvalidValue(String, String),
+}
+
+pub struct Config {
+    // number of channels captured, 2 enables the stereo views of the visualizer
+    pub channels: usize,
+    pub leds_target: String,
+    // spectrum, key, pulse or off
+    pub leds_effect: lightsd::Effect,
+    // frame rate limits for the sinks, `None` delivers every frame
+    pub leds_fps: Option<u32>,
+    pub visual_fps: Option<u32>,
+    // hold frames back so the sinks line up with delayed audio playback
+    pub leds_delay: Duration,
+    pub visual_delay: Duration,
+    // one window per mode
+    pub visual_modes: Vec<visual::Mode>,
+    // size of the windows in logical pixels
+    pub window_size: (u32, u32),
+    // monitor used for fullscreen, `None` for the primary one
+    pub monitor: Option<usize>,
+    pub fullscreen: bool,
+    // show the calibration overlay from the start
+    pub overlay: bool,
+    // beats after which the visualizer switches to the next preset, `None` disables it
+    pub visual_rotate: Option<u32>,
+    // number of frames kept by the waterfall, halved until the texture fits the driver
+    pub waterfall_history: usize,
+    pub colormap: visual::Colormap,
+    pub amplitude: visual::Amplitude,
+    // directory user shaders are loaded from and reloaded when saved
+    pub shader_dir: Option<PathBuf>,
+    pub shader: Option<String>,
+    // audio file to render to video instead of visualizing the live input
+    pub render_input: Option<String>,
+    // PNG file name pattern, or the pipeline the frames are encoded with instead, or a video
+    // file the frames are muxed into together with the audio
+    pub render_output: String,
+    pub render_pipeline: Option<String>,
+    pub render_video: Option<String>,
+    pub render_fps: u32,
+    pub render_size: (u32, u32),
+    // cue list applied to the presets and the LEDs, `None` disables cues
+    pub cues: Option<String>,
+    // raw MIDI device the cues take their MIDI timecode from instead of the frame timestamps
+    pub mtc_input: Option<String>,
+    // channel of the audio input carrying linear timecode for the cues, 0 is the left or only
+    // one, `None` disables decoding
+    pub ltc_channel: Option<usize>,
+    // file the raw samples are recorded to, `None` disables recording
+    pub record: Option<String>,
+    // file the analyzed frames are recorded to, `None` disables recording
+    pub record_frames: Option<String>,
+    // recording analyzed instead of the live input, or recorded frames handed to the sinks
+    // instead of analyzing anything, and how much faster they are replayed
+    pub replay: Option<String>,
+    pub replay_frames: Option<String>,
+    pub replay_speed: f32,
+    // address of the websocket server, `None` disables it
+    pub websocket: Option<String>,
+    // target of the OSC sink, `None` disables it
+    pub osc_target: Option<String>,
+    pub osc_prefix: String,
+    pub osc_bands: usize,
+    pub osc_fps: Option<u32>,
+    // raw MIDI device, `None` disables MIDI output
+    pub midi_output: Option<String>,
+    pub midi_channel: u8,
+    pub midi_threshold: f32,
+    pub midi_bands: usize,
+    // file the features of every frame are written to, `None` disables the export
+    pub features_csv: Option<String>,
+    // draw the spectrum in the terminal
+    pub terminal: bool,
+    pub terminal_rows: usize,
+    pub terminal_fps: Option<u32>,
+    // address of the HTTP control server, `None` disables it
+    pub control: Option<String>,
+    // how often sink latency and dropped frames are reported, `None` disables reporting
+    pub stats_interval: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            channels: 1,
+            leds_target: "172.20.64.232:1337".to_string(),
+            leds_effect: lightsd::Effect::Spectrum,
+            leds_fps: Some(60),
+            visual_fps: None,
+            leds_delay: Duration::new(0, 0),
+            visual_delay: Duration::new(0, 0),
+            visual_modes: vec![visual::Mode::Spectrum],
+            window_size: (1024, 786),
+            monitor: None,
+            fullscreen: false,
+            overlay: false,
+            visual_rotate: None,
+            waterfall_history: 256,
+            colormap: visual::Colormap::Viridis,
+            amplitude: visual::Amplitude::Log,
+            shader_dir: None,
+            shader: None,
+            render_input: None,
+            render_output: "frame%05d.png".to_string(),
+            render_pipeline: None,
+            render_video: None,
+            render_fps: 30,
+            render_size: (1280, 720),
+            cues: None,
+            mtc_input: None,
+            ltc_channel: None,
+            record: None,
+            record_frames: None,
+            replay: None,
+            replay_frames: None,
+            replay_speed: 1.0,
+            websocket: None,
+            osc_target: None,
+            osc_prefix: "/soundvis".to_string(),
+            osc_bands: 8,
+            osc_fps: Some(30),
+            midi_output: None,
+            midi_channel: 1,
+            midi_threshold: 0.6,
+            midi_bands: 8,
+            features_csv: None,
+            terminal: false,
+            terminal_rows: 16,
+            terminal_fps: Some(30),
+            control: None,
+            stats_interval: None,
+        }
+    }
+}
+
+fn parse<T: ::std::str::FromStr>(option: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| {
+        ConfigError::InvalidValue(option.to_string(), value.to_string())
+    })
+}
+
+// a frame rate of zero means unlimited
+fn parse_fps(option: &str, value: &str) -> Result<Option<u32>, ConfigError> {
+    parse(option, value).map(|fps| if fps == 0 { None } else { Some(fps) })
+}
+
+// e.g. 1280x720
+fn parse_size(option: &str, value: &str) -> Result<(u32, u32), ConfigError> {
+    let invalid = || ConfigError::InvalidValue(option.to_string(), value.to_string());
+    let mut parts = value.splitn(2, 'x');
+    match (parts.next(), parts.next()) {
+        (Some(width), Some(height)) => match (width.parse(), height.parse()) {
+            (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+            _ => Err(invalid()),
+        },
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_millis(option: &str, value: &str) -> Result<Duration, ConfigError> {
+    parse(option, value).map(Duration::from_millis)
+}
+
+impl Config {
+    pub fn visual_settings(&self) -> visual::Settings {
+        visual::Settings {
+            mode: self.visual_modes[0],
+            size: self.window_size,
+            monitor: self.monitor,
+            fullscreen: self.fullscreen,
+            overlay: self.overlay,
+            history: self.waterfall_history,
+            colormap: self.colormap,
+            amplitude: self.amplitude,
+            shader_dir: self.shader_dir.clone(),
+            shader: self.shader.clone(),
+            rotate_beats: self.visual_rotate,
+        }
+    }
+
+    pub fn from_args() -> Result<Config, Error> {
+        Config::parse(env::args().skip(1))
+    }
+
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Config, Error> {
+        let mut config = Config::default();
+
+        while let Some(option) = args.next() {
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(ConfigError::MissingValue(option).into()),
+            };
+            match option.as_str() {
+                "--channels" => {
+                    config.channels = parse(&option, &value)?;
+                    if config.channels < 1 || config.channels > 2 {
+                        return Err(ConfigError::InvalidValue(option, value).into());
+                    }
+                }
+                "--leds" => config.leds_target = value,
+                "--leds-effect" => config.leds_effect = parse(&option, &value)?,
+                "--leds-fps" => config.leds_fps = parse_fps(&option, &value)?,
+                "--visual-fps" => config.visual_fps = parse_fps(&option, &value)?,
+                "--leds-delay" => config.leds_delay = parse_millis(&option, &value)?,
+                "--visual-delay" => config.visual_delay = parse_millis(&option, &value)?,
+                "--visual-mode" => {
+                    config.visual_modes = value
+                        .split(',')
+                        .map(|mode| parse(&option, mode))
+                        .collect::<Result<_, _>>()?
+                }
+                "--window-size" => config.window_size = parse_size(&option, &value)?,
+                "--monitor" => config.monitor = Some(parse(&option, &value)?),
+                "--fullscreen" => config.fullscreen = parse(&option, &value)?,
+                "--overlay" => config.overlay = parse(&option, &value)?,
+                "--visual-rotate" => {
+                    let beats = parse(&option, &value)?;
+                    config.visual_rotate = if beats == 0 { None } else { Some(beats) };
+                }
+                "--waterfall-history" => {
+                    config.waterfall_history = parse(&option, &value)?;
+                    if config.waterfall_history == 0 {
+                        return Err(ConfigError::InvalidValue(option, value).into());
+                    }
+                }
+                "--colormap" => config.colormap = parse(&option, &value)?,
+                "--amplitude" => config.amplitude = parse(&option, &value)?,
+                "--shader-dir" => config.shader_dir = Some(PathBuf::from(value)),
+                "--shader" => config.shader = Some(value),
+                "--render" => config.render_input = Some(value),
+                "--render-output" => config.render_output = value,
+                "--render-pipeline" => config.render_pipeline = Some(value),
+                "--render-video" => config.render_video = Some(value),
+                "--render-fps" => {
+                    config.render_fps = parse(&option, &value)?;
+                    if config.render_fps == 0 {
+                        return Err(ConfigError::InvalidValue(option, value).into());
+                    }
+                }
+                "--render-size" => {
+                    config.render_size = parse_size(&option, &value)?;
+                    // the 4:2:0 chroma subsampling of x264 needs even dimensions
+                    if config.render_size.0 % 2 != 0 || config.render_size.1 % 2 != 0 {
+                        return Err(ConfigError::InvalidValue(option, value).into());
+                    }
+                }
+                "--cues" => config.cues = Some(value),
+                "--mtc" => config.mtc_input = Some(value),
+                "--ltc" => config.ltc_channel = Some(parse(&option, &value)?),
+                "--record" => config.record = Some(value),
+                "--record-frames" => config.record_frames = Some(value),
+                "--replay" => config.replay = Some(value),
+                "--replay-frames" => config.replay_frames = Some(value),
+                "--replay-speed" => {
+                    config.replay_speed = parse(&option, &value)?;
+                    if !(config.replay_speed > 0.0) {
+                        return Err(ConfigError::InvalidValue(option, value).into());
+                    }
+                }
+                "--websocket" => config.websocket = Some(value),
+                "--osc" => config.osc_target = Some(value),
+                "--osc-prefix" => config.osc_prefix = value,
+                "--osc-bands" => config.osc_bands = parse(&option, &value)?,
+                "--osc-fps" => config.osc_fps = parse_fps(&option, &value)?,
+                "--midi" => config.midi_output = Some(value),
+                "--midi-channel" => {
+                    config.midi_channel = parse(&option, &value)?;
+                    if config.midi_channel < 1 || config.midi_channel > 16 {
+                        return Err(ConfigError::InvalidValue(option, value).into());
+                    }
+                }
+                "--midi-threshold" => config.midi_threshold = parse(&option, &value)?,
+                "--midi-bands" => config.midi_bands = parse(&option, &value)?,
+                "--features-csv" => config.features_csv = Some(value),
+                "--terminal" => config.terminal = parse(&option, &value)?,
+                "--terminal-rows" => config.terminal_rows = parse(&option, &value)?,
+                "--terminal-fps" => config.terminal_fps = parse_fps(&option, &value)?,
+                "--control" => config.control = Some(value),
+                "--stats-interval" => {
+                    let secs: u64 = parse(&option, &value)?;
+                    config.stats_interval = if secs == 0 {
+                        None
+                    } else {
+                        Some(Duration::from_secs(secs))
+                    };
+                }
+                _ => return Err(ConfigError::UnknownOption(option).into()),
+            }
+        }
+
+        // the timecode channel has to be captured, replayed frames carry no audio and the cues
+        // can only follow one timecode
+        if let Some(channel) = config.ltc_channel {
+            let unavailable = config.replay_frames.is_some() || config.mtc_input.is_some();
+            if channel >= config.channels || unavailable {
+                let value = channel.to_string();
+                return Err(ConfigError::InvalidValue("--ltc".to_string(), value).into());
+            }
+        }
+
+        Ok(config)
+    }
+}
@@ -0,0 +1,422 @@
+// Control of the live parameters over HTTP.
+//
+//   GET  /parameters   the current parameters as JSON
+//   POST /parameters   change some of them, e.g. `{"gain": 2, "leds.effect": "pulse"}`
+//
+// The parameters have the names and values of the cue lists: window, agc (on/off),
+// agc.decay, preset, gain, rotate (on/off), overlay (on/off), leds.effect, leds.brightness
+// and leds.hue. The changes of a request are validated together and either all or none are
+// applied, each sink picks them up when it handles its next frame. Every parameter of a
+// request is applied, even when it already has the value. Cues and keys pressed in the
+// windows report their changes back so the parameters show what the sinks are using, the
+// presets switched by the beat rotation are left out.
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use failure::Error;
+use serde::Serializer;
+use serde_json::{self, Value};
+
+use cues::{self, Change};
+use lightsd::Effect;
+use visual::Mode;
+
+// upper bound of the size of a request body
+const MAX_BODY: usize = 64 * 1024;
+// updates kept for watchers that fall behind, they get all parameters otherwise
+const MAX_UPDATES: usize = 64;
+// requests are handled one after another, a client may not keep the server waiting longer
+const REQUEST_TIMEOUT: u64 = 2;
+
+#[derive(Debug, Fail)]
+pub enum ControlError {
+    #[fail(display = "the parameters must be a JSON object")]
+    NotAnObject,
+    #[fail(display = "invalid value {} of parameter {}", _1, _0)]
+    InvalidValue(String, String),
+}
+
+fn serialize_mode<S: Serializer>(mode: &Mode, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(mode.name())
+}
+
+fn serialize_effect<S: Serializer>(effect: &Effect, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(effect.name())
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Parameters {
+    pub window: usize,
+    pub agc: bool,
+    #[serde(rename = "agc.decay")]
+    pub agc_decay: f32,
+    #[serde(serialize_with = "serialize_mode")]
+    pub preset: Mode,
+    pub gain: f32,
+    pub rotate: bool,
+    pub overlay: bool,
+    #[serde(rename = "leds.effect", serialize_with = "serialize_effect")]
+    pub leds_effect: Effect,
+    #[serde(rename = "leds.brightness")]
+    pub leds_brightness: f32,
+    #[serde(rename = "leds.hue")]
+    pub leds_hue: f32,
+}
+
+impl Parameters {
+    fn apply(&mut self, change: &Change) {
+        match *change {
+            Change::Window(window) => self.window = window,
+            Change::Agc(agc) => self.agc = agc,
+            Change::AgcDecay(decay) => self.agc_decay = decay,
+            Change::Preset(mode) => self.preset = mode,
+            Change::Gain(gain) => self.gain = gain,
+            Change::Rotate(rotate) => self.rotate = rotate,
+            Change::Overlay(overlay) => self.overlay = overlay,
+            Change::LedEffect(effect) => self.leds_effect = effect,
+            Change::LedBrightness(brightness) => self.leds_brightness = brightness,
+            Change::LedHue(hue) => self.leds_hue = hue,
+        }
+    }
+
+    // the changes that set every parameter
+    fn changes(&self) -> Vec<Change> {
+        vec![
+            Change::Window(self.window),
+            Change::Agc(self.agc),
+            Change::AgcDecay(self.agc_decay),
+            Change::Preset(self.preset),
+            Change::Gain(self.gain),
+            Change::Rotate(self.rotate),
+            Change::Overlay(self.overlay),
+            Change::LedEffect(self.leds_effect),
+            Change::LedBrightness(self.leds_brightness),
+            Change::LedHue(self.leds_hue),
+        ]
+    }
+}
+
+struct State {
+    parameters: Parameters,
+    // number of the last update
+    generation: u64,
+    // the changes of the most recent updates by their number
+    updates: VecDeque<(u64, Vec<Change>)>,
+}
+
+// the parameters shared by the server and the sinks
+#[derive(Clone)]
+pub struct Control {
+    state: Arc<Mutex<State>>,
+}
+
+impl Control {
+    pub fn new(parameters: Parameters) -> Control {
+        Control {
+            state: Arc::new(Mutex::new(State {
+                parameters: parameters,
+                generation: 0,
+                updates: VecDeque::new(),
+            })),
+        }
+    }
+
+    pub fn parameters(&self) -> Parameters {
+        self.state.lock().unwrap().parameters.clone()
+    }
+
+    // record a change a sink made on its own, e.g. by a cue, without passing it on
+    pub fn note(&self, change: &Change) {
+        self.state.lock().unwrap().parameters.apply(change);
+    }
+
+    // apply the parameters of the JSON object `patch`, nothing is changed if any is invalid
+    pub fn update(&self, patch: &Value) -> Result<Parameters, ControlError> {
+        let patch = match *patch {
+            Value::Object(ref patch) => patch,
+            _ => return Err(ControlError::NotAnObject),
+        };
+        let mut state = self.state.lock().unwrap();
+        let mut parameters = state.parameters.clone();
+        let mut changes = vec![];
+        for (name, value) in patch.iter() {
+            let text = match *value {
+                Value::String(ref s) => s.clone(),
+                Value::Bool(b) => (if b { "on" } else { "off" }).to_string(),
+                Value::Number(ref n) => n.to_string(),
+                _ => String::new(),
+            };
+            match cues::parse_change(name, &text) {
+                Some(change) => {
+                    parameters.apply(&change);
+                    changes.push(change);
+                }
+                None => return Err(ControlError::InvalidValue(name.clone(), value.to_string())),
+            }
+        }
+        state.generation += 1;
+        let generation = state.generation;
+        state.updates.push_back((generation, changes));
+        if state.updates.len() > MAX_UPDATES {
+            state.updates.pop_front();
+        }
+        state.parameters = parameters.clone();
+        Ok(parameters)
+    }
+
+    pub fn watch(&self) -> Watcher {
+        Watcher {
+            control: self.clone(),
+            generation: self.state.lock().unwrap().generation,
+        }
+    }
+}
+
+// the view of a sink on the parameters
+pub struct Watcher {
+    control: Control,
+    // number of the last update passed on
+    generation: u64,
+}
+
+impl Watcher {
+    pub fn parameters(&self) -> Parameters {
+        self.control.parameters()
+    }
+
+    pub fn note(&self, change: &Change) {
+        self.control.note(change)
+    }
+
+    // the changes of the updates since the last call
+    pub fn changes(&mut self) -> Vec<Change> {
+        let state = self.control.state.lock().unwrap();
+        if state.generation == self.generation {
+            return vec![];
+        }
+        let missed = state
+            .updates
+            .front()
+            .map(|update| update.0 > self.generation + 1)
+            .unwrap_or(true);
+        let changes = if missed {
+            state.parameters.changes()
+        } else {
+            state
+                .updates
+                .iter()
+                .filter(|update| update.0 > self.generation)
+                .flat_map(|update| update.1.iter().cloned())
+                .collect()
+        };
+        self.generation = state.generation;
+        changes
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<(), Error> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ErrorMessage<'a> {
+    error: &'a str,
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::to_string(&ErrorMessage { error: message }).unwrap_or_default()
+}
+
+fn handle(mut stream: TcpStream, control: &Control) -> Result<(), Error> {
+    let (method, path, body) = {
+        let mut reader = BufReader::new(&stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+
+        // POST /parameters HTTP/1.1
+        let (method, path) = {
+            let mut request = line.split_whitespace();
+            (
+                request.next().unwrap_or("").to_string(),
+                request.next().unwrap_or("/").to_string(),
+            )
+        };
+
+        let mut length = 0;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            let mut header = line.splitn(2, ':');
+            if let (Some(name), Some(value)) = (header.next(), header.next()) {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0; usize::min(length, MAX_BODY)];
+        reader.read_exact(&mut body)?;
+        (method, path, body)
+    };
+
+    match (method.as_str(), path.as_str()) {
+        ("GET", "/parameters") => {
+            let body = serde_json::to_string(&control.parameters())?;
+            respond(&mut stream, "200 OK", &body)
+        }
+        ("POST", "/parameters") | ("PUT", "/parameters") => {
+            let result = serde_json::from_slice::<Value>(&body)
+                .map_err(|e| e.to_string())
+                .and_then(|patch| control.update(&patch).map_err(|e| e.to_string()));
+            match result {
+                Ok(parameters) => {
+                    let body = serde_json::to_string(&parameters)?;
+                    respond(&mut stream, "200 OK", &body)
+                }
+                Err(e) => respond(&mut stream, "400 Bad Request", &error_body(&e)),
+            }
+        }
+        (_, "/parameters") => respond(
+            &mut stream,
+            "405 Method Not Allowed",
+            &error_body("use GET or POST"),
+        ),
+        _ => respond(&mut stream, "404 Not Found", &error_body("not found")),
+    }
+}
+
+// requests are handled one after another, they are short
+pub fn serve(address: String, control: Control) {
+    let listener = match TcpListener::bind(&address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Failed to listen for control requests on {}: {}", address, e);
+            return;
+        }
+    };
+
+    let timeout = Some(Duration::from_secs(REQUEST_TIMEOUT));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        if let Err(e) = stream
+            .set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+        {
+            println!("Failed to set the timeout of a control request: {}", e);
+            continue;
+        }
+        if let Err(e) = handle(stream, &control) {
+            println!("Failed to handle a control request: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Control, Parameters};
+    use cues::Change;
+    use serde_json;
+    use lightsd::Effect;
+    use visual::Mode;
+
+    fn parameters() -> Parameters {
+        Parameters {
+            window: 13,
+            agc: true,
+            agc_decay: 0.99,
+            preset: Mode::Spectrum,
+            gain: 1.0,
+            rotate: false,
+            overlay: false,
+            leds_effect: Effect::Spectrum,
+            leds_brightness: 1.0,
+            leds_hue: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_updates_are_all_or_nothing() {
+        let control = Control::new(parameters());
+        let mut watcher = control.watch();
+
+        let invalid = serde_json::from_str(r#"{"gain": 2, "leds.effect": "strobe"}"#).unwrap();
+        assert!(control.update(&invalid).is_err());
+        assert_eq!(watcher.changes(), vec![]);
+
+        let valid = serde_json::from_str(r#"{"gain": 2, "leds.effect": "pulse", "overlay": true}"#)
+            .unwrap();
+        assert!(control.update(&valid).is_ok());
+        assert_eq!(
+            watcher.changes(),
+            vec![
+                Change::Gain(2.0),
+                Change::LedEffect(Effect::Pulse),
+                Change::Overlay(true),
+            ]
+        );
+        assert_eq!(watcher.changes(), vec![]);
+    }
+
+    #[test]
+    fn test_values_are_validated() {
+        let control = Control::new(parameters());
+        let mut watcher = control.watch();
+
+        for invalid in &[
+            r#"{"gain": "NaN"}"#,
+            r#"{"gain": -1}"#,
+            r#"{"agc.decay": "inf"}"#,
+            r#"{"leds.brightness": 1.5}"#,
+            r#"{"leds.hue": "-inf"}"#,
+            r#"{"window": 13.5}"#,
+        ] {
+            let invalid = serde_json::from_str(invalid).unwrap();
+            assert!(control.update(&invalid).is_err());
+        }
+        assert_eq!(watcher.changes(), vec![]);
+
+        let valid = serde_json::from_str(r#"{"window": 14.0, "leds.hue": -90}"#).unwrap();
+        assert!(control.update(&valid).is_ok());
+        assert_eq!(
+            watcher.changes(),
+            vec![Change::LedHue(-90.0), Change::Window(14)]
+        );
+    }
+
+    #[test]
+    fn test_unchanged_values_are_applied() {
+        let control = Control::new(parameters());
+        let mut watcher = control.watch();
+
+        // a cue changed the gain in the meantime
+        control.note(&Change::Gain(3.0));
+        assert_eq!(control.parameters().gain, 3.0);
+        assert_eq!(watcher.changes(), vec![]);
+
+        let gain = serde_json::from_str(r#"{"gain": 1}"#).unwrap();
+        assert!(control.update(&gain).is_ok());
+        assert!(control.update(&gain).is_ok());
+        assert_eq!(
+            watcher.changes(),
+            vec![Change::Gain(1.0), Change::Gain(1.0)]
+        );
+    }
+}
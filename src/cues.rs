@@ -0,0 +1,605 @@
+// Show control: cue lists that change the presets and the LEDs at fixed points of a song.
+//
+// A cue list is a text file with one cue per line, the time followed by the change:
+//   # comments and empty lines are ignored
+//   0:00        preset spectrum
+//   0:32.5      gain 2
+//   1:04        rotate on
+//   1:04        overlay off
+//   1:04        leds.brightness 0.5
+//   2:08        leds.hue 120
+//   2:08        leds.effect pulse
+//   3:00        window 14
+//   3:00        agc off
+// Times are seconds, minutes:seconds or hours:minutes:seconds. Cues of the same time are
+// applied in the order of the file, preset changes apply to every window. The gain is at
+// least 0, agc.decay above 0 and at most 1, leds.brightness from 0 to 1 and leds.hue is in
+// degrees.
+//
+// The position in the song is either the timestamp of the frames, MIDI timecode received on a
+// raw MIDI device or linear timecode on a channel of the audio input. The timestamps are the
+// position in the file when rendering or replaying, for live input they count from the start
+// of the capture, so the cue list only lines up with a song started along with the program.
+// Jumping back, e.g. when the timecode is rewound, applies every cue up to the new position
+// again, changes of the later cues are not undone.
+
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+
+use analysis::{MAX_WINDOW, MIN_WINDOW};
+use lightsd::Effect;
+use visual::Mode;
+
+const QUARTER_FRAME: u8 = 0xf1;
+const SYSEX_START: u8 = 0xf0;
+const SYSEX_END: u8 = 0xf7;
+// messages from 0xf8 on are real time messages which may appear anywhere, even within sysex
+const REAL_TIME: u8 = 0xf8;
+// universal real time sysex, any device, MIDI time code, full frame
+const FULL_FRAME: [u8; 4] = [0x7f, 0x7f, 0x01, 0x01];
+// a linear timecode frame has 80 bits and ends with the sync word
+const LTC_BITS: usize = 80;
+const LTC_SYNC: [bool; 16] = [
+    false, false, true, true, true, true, true, true, true, true, true, true, true, true, false,
+    true,
+];
+// the signal has to cross this level to count as a transition
+const LTC_HYSTERESIS: f32 = 0.02;
+
+#[derive(Debug, Fail)]
+pub enum CueError {
+    #[fail(display = "invalid cue in line {}: {}", _0, _1)]
+    InvalidCue(usize, String),
+}
+
+// a change of a parameter, by a cue or through the control API
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    // exponent of the largest fft of the analysis
+    Window(usize),
+    Agc(bool),
+    AgcDecay(f32),
+    Preset(Mode),
+    Gain(f32),
+    Rotate(bool),
+    Overlay(bool),
+    LedEffect(Effect),
+    LedBrightness(f32),
+    LedHue(f32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cue {
+    // position in the song in nanoseconds
+    pub time: u64,
+    pub change: Change,
+}
+
+// e.g. "95", "1:35" or "0:01:35.5"
+fn parse_time(s: &str) -> Option<u64> {
+    let mut seconds = 0.0;
+    for part in s.split(':') {
+        let value: f64 = part.parse().ok()?;
+        if value < 0.0 {
+            return None;
+        }
+        seconds = seconds * 60.0 + value;
+    }
+    Some((seconds * 1_000_000_000.0) as u64)
+}
+
+fn parse_switch(s: &str) -> Option<bool> {
+    match s {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+// a finite number for which `valid` holds
+fn parse_number<F: Fn(f32) -> bool>(s: &str, valid: F) -> Option<f32> {
+    s.parse()
+        .ok()
+        .and_then(|n: f32| if n.is_finite() && valid(n) { Some(n) } else { None })
+}
+
+// the change of the parameter `name`, `None` for unknown parameters and invalid values
+pub fn parse_change(name: &str, value: &str) -> Option<Change> {
+    match name {
+        // integral numbers like `13.0` are accepted as well, JSON does not tell them apart
+        "window" => parse_number(value, |w| w.fract() == 0.0)
+            .map(|w| w as usize)
+            .and_then(|w| if w >= MIN_WINDOW && w <= MAX_WINDOW { Some(w) } else { None })
+            .map(Change::Window),
+        "agc" => parse_switch(value).map(Change::Agc),
+        "agc.decay" => parse_number(value, |d| d > 0.0 && d <= 1.0).map(Change::AgcDecay),
+        "preset" => value.parse().ok().map(Change::Preset),
+        "gain" => parse_number(value, |g| g >= 0.0).map(Change::Gain),
+        "rotate" => parse_switch(value).map(Change::Rotate),
+        "overlay" => parse_switch(value).map(Change::Overlay),
+        "leds.effect" => value.parse().ok().map(Change::LedEffect),
+        "leds.brightness" => {
+            parse_number(value, |b| b >= 0.0 && b <= 1.0).map(Change::LedBrightness)
+        }
+        "leds.hue" => parse_number(value, |_| true).map(Change::LedHue),
+        _ => None,
+    }
+}
+
+// the cues of a cue list ordered by time
+pub fn parse(text: &str) -> Result<Vec<Cue>, CueError> {
+    let mut cues = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.splitn(2, '#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let cue = if parts.len() == 3 {
+            parse_time(parts[0]).and_then(|time| {
+                parse_change(parts[1], parts[2]).map(|change| Cue {
+                    time: time,
+                    change: change,
+                })
+            })
+        } else {
+            None
+        };
+        match cue {
+            Some(cue) => cues.push(cue),
+            None => return Err(CueError::InvalidCue(number + 1, line.to_string())),
+        }
+    }
+    // stable, cues of the same time keep their order
+    cues.sort_by_key(|cue| cue.time);
+    Ok(cues)
+}
+
+pub fn load(path: &str) -> Result<Vec<Cue>, Error> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    Ok(parse(&text)?)
+}
+
+// where the position in the song comes from
+#[derive(Clone)]
+pub enum Timecode {
+    // the timestamp of the frames
+    Position,
+    // the latest MIDI timecode in nanoseconds, `None` until the first one arrived
+    Midi(Arc<Mutex<Option<u64>>>),
+    // the latest linear timecode of the audio input, like the MIDI timecode
+    Linear(Arc<Mutex<Option<u64>>>),
+}
+
+impl Timecode {
+    // the position in the song, `pts` is the timestamp of the latest frame
+    pub fn position(&self, pts: Option<u64>) -> Option<u64> {
+        match *self {
+            Timecode::Position => pts,
+            Timecode::Midi(ref time) | Timecode::Linear(ref time) => *time.lock().unwrap(),
+        }
+    }
+}
+
+// a cue list and the clock it follows, handed to every sink that applies cues
+#[derive(Clone)]
+pub struct Show {
+    pub cues: Vec<Cue>,
+    pub timecode: Timecode,
+}
+
+// applies the cues of a show as its position advances
+pub struct CuePlayer {
+    show: Show,
+    // index of the first cue not applied yet
+    next: usize,
+    position: Option<u64>,
+}
+
+impl CuePlayer {
+    pub fn new(show: Show) -> CuePlayer {
+        CuePlayer {
+            show: show,
+            next: 0,
+            position: None,
+        }
+    }
+
+    // the changes due at the position of the frame with the timestamp `pts`
+    pub fn advance(&mut self, pts: Option<u64>) -> Vec<Change> {
+        let position = self.show.timecode.position(pts);
+        self.seek(position)
+    }
+
+    // the changes of the cues passed since the previous position, all cues up to `position`
+    // again when it went back
+    pub fn seek(&mut self, position: Option<u64>) -> Vec<Change> {
+        let position = match position {
+            Some(position) => position,
+            None => return vec![],
+        };
+        if self.position.map(|p| position < p).unwrap_or(false) {
+            self.next = 0;
+        }
+        self.position = Some(position);
+
+        let from = self.next;
+        while self.next < self.show.cues.len() && self.show.cues[self.next].time <= position {
+            self.next += 1;
+        }
+        self.show.cues[from..self.next]
+            .iter()
+            .map(|cue| cue.change.clone())
+            .collect()
+    }
+}
+
+fn frame_rate(rate: u8) -> f64 {
+    match rate & 0x03 {
+        0 => 24.0,
+        1 => 25.0,
+        2 => 30000.0 / 1001.0,
+        _ => 30.0,
+    }
+}
+
+fn timecode_nanos(hours: u8, minutes: u8, seconds: u8, frames: f64, rate: u8) -> u64 {
+    let seconds = (hours as u64 * 60 + minutes as u64) * 60 + seconds as u64;
+    seconds * 1_000_000_000 + (frames / frame_rate(rate) * 1_000_000_000.0).round() as u64
+}
+
+// decodes MIDI time code from a stream of raw MIDI bytes, both the quarter frame messages of
+// a running clock and the full frame sysex sent when it is located
+pub struct MtcDecoder {
+    pieces: [u8; 8],
+    // bit n is set once piece n of the current cycle was received
+    received: u8,
+    sysex: Option<Vec<u8>>,
+    quarter_frame: bool,
+}
+
+impl MtcDecoder {
+    pub fn new() -> MtcDecoder {
+        MtcDecoder {
+            pieces: [0; 8],
+            received: 0,
+            sysex: None,
+            quarter_frame: false,
+        }
+    }
+
+    // the time in nanoseconds when `byte` completed a timecode
+    pub fn push(&mut self, byte: u8) -> Option<u64> {
+        if byte >= REAL_TIME {
+            return None;
+        }
+        if self.quarter_frame {
+            self.quarter_frame = false;
+            if byte & 0x80 == 0 {
+                return self.quarter(byte);
+            }
+        }
+        match byte {
+            QUARTER_FRAME => {
+                self.sysex = None;
+                self.quarter_frame = true;
+                None
+            }
+            SYSEX_START => {
+                self.sysex = Some(vec![]);
+                None
+            }
+            SYSEX_END => match self.sysex.take() {
+                Some(sysex) => self.full_frame(&sysex),
+                None => None,
+            },
+            byte if byte & 0x80 != 0 => {
+                // any other status byte ends a sysex
+                self.sysex = None;
+                None
+            }
+            byte => {
+                if let Some(ref mut sysex) = self.sysex {
+                    sysex.push(byte);
+                }
+                None
+            }
+        }
+    }
+
+    fn quarter(&mut self, data: u8) -> Option<u64> {
+        let piece = (data >> 4) as usize & 0x07;
+        if piece == 0 {
+            self.received = 0;
+        }
+        self.pieces[piece] = data & 0x0f;
+        self.received |= 1 << piece;
+        if piece != 7 || self.received != 0xff {
+            return None;
+        }
+
+        let p = &self.pieces;
+        let frames = p[0] | (p[1] & 0x01) << 4;
+        let seconds = p[2] | (p[3] & 0x03) << 4;
+        let minutes = p[4] | (p[5] & 0x03) << 4;
+        let hours = p[6] | (p[7] & 0x01) << 4;
+        let rate = (p[7] >> 1) & 0x03;
+        // the eight pieces take two frames, the time they carry is the start of the first
+        Some(timecode_nanos(hours, minutes, seconds, frames as f64 + 2.0, rate))
+    }
+
+    fn full_frame(&self, sysex: &[u8]) -> Option<u64> {
+        if sysex.len() != 8 || sysex[..4] != FULL_FRAME[..] {
+            return None;
+        }
+        let hours = sysex[4] & 0x1f;
+        let rate = (sysex[4] >> 5) & 0x03;
+        Some(timecode_nanos(hours, sysex[5], sysex[6], sysex[7] as f64, rate))
+    }
+}
+
+// decodes linear timecode (SMPTE 12M) from the samples of an audio channel, the bits are
+// biphase mark coded: the level flips at the start of every bit and in the middle of ones
+pub struct LtcDecoder {
+    sample_rate: usize,
+    // level after the previous transition
+    high: bool,
+    // samples since the previous transition
+    since: usize,
+    // estimated samples per bit
+    period: f32,
+    // the first half of a one was received
+    half: bool,
+    bits: Vec<bool>,
+}
+
+impl LtcDecoder {
+    pub fn new(sample_rate: usize) -> LtcDecoder {
+        LtcDecoder {
+            sample_rate: sample_rate,
+            high: false,
+            since: 0,
+            // 25 fps until the bits have been measured
+            period: sample_rate as f32 / (25 * LTC_BITS) as f32,
+            half: false,
+            bits: Vec::with_capacity(LTC_BITS),
+        }
+    }
+
+    // the time in nanoseconds after the last frame completed within `samples`
+    pub fn push(&mut self, samples: &[f32]) -> Option<u64> {
+        let mut time = None;
+        for &sample in samples.iter() {
+            self.since += 1;
+            let high = if sample > LTC_HYSTERESIS {
+                true
+            } else if sample < -LTC_HYSTERESIS {
+                false
+            } else {
+                continue;
+            };
+            if high != self.high {
+                self.high = high;
+                let interval = self.since as f32;
+                self.since = 0;
+                if let Some(t) = self.transition(interval) {
+                    time = Some(t);
+                }
+            }
+        }
+        time
+    }
+
+    fn transition(&mut self, interval: f32) -> Option<u64> {
+        // a gap in the signal or the second half of a one where a whole bit was expected
+        let lost = interval > 2.0 * self.period || (self.half && interval > 0.75 * self.period);
+        if lost {
+            self.half = false;
+            self.bits.clear();
+            return None;
+        }
+        let bit = if interval > 0.75 * self.period {
+            self.period = 0.9 * self.period + 0.1 * interval;
+            false
+        } else {
+            self.period = 0.9 * self.period + 0.1 * 2.0 * interval;
+            self.half = !self.half;
+            if self.half {
+                return None;
+            }
+            true
+        };
+        if self.bits.len() == LTC_BITS {
+            self.bits.remove(0);
+        }
+        self.bits.push(bit);
+        if self.bits.len() < LTC_BITS || self.bits[LTC_BITS - LTC_SYNC.len()..] != LTC_SYNC[..] {
+            return None;
+        }
+        self.decode()
+    }
+
+    fn decode(&self) -> Option<u64> {
+        // binary coded decimal digits, least significant bit first
+        let digit = |start: usize, count: usize| {
+            (0..count).fold(0, |value, i| value | (self.bits[start + i] as u8) << i)
+        };
+        let frames = digit(0, 4) + 10 * digit(8, 2);
+        let drop_frame = self.bits[10];
+        let seconds = digit(16, 4) + 10 * digit(24, 3);
+        let minutes = digit(32, 4) + 10 * digit(40, 3);
+        let hours = digit(48, 4) + 10 * digit(56, 2);
+        if frames >= 30 || seconds >= 60 || minutes >= 60 || hours >= 24 {
+            return None;
+        }
+        // the frame rate follows from the bit rate, 29.97 fps is the one with drop frames
+        let fps = self.sample_rate as f32 / (self.period * LTC_BITS as f32);
+        let rate = if fps < 24.5 {
+            0
+        } else if fps < 27.5 {
+            1
+        } else if drop_frame {
+            2
+        } else {
+            3
+        };
+        // the sync word ends the frame, the next one starts now
+        Some(timecode_nanos(hours, minutes, seconds, frames as f64 + 1.0, rate))
+    }
+}
+
+// follow the MIDI time code sent to the raw MIDI device at `path`
+pub fn read_mtc(path: String, time: Arc<Mutex<Option<u64>>>) {
+    let mut input = match File::open(&path) {
+        Ok(input) => input,
+        Err(e) => {
+            println!("Failed to open MIDI input {}: {}", path, e);
+            return;
+        }
+    };
+    let mut decoder = MtcDecoder::new();
+    let mut buffer = [0; 64];
+    loop {
+        let count = match input.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(count) => count,
+            Err(e) => {
+                println!("Failed to read MIDI input {}: {}", path, e);
+                return;
+            }
+        };
+        for &byte in buffer[..count].iter() {
+            if let Some(nanos) = decoder.push(byte) {
+                *time.lock().unwrap() = Some(nanos);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Change, Cue, CuePlayer, LtcDecoder, MtcDecoder, Show, Timecode, LTC_BITS,
+                LTC_SYNC};
+    use visual::Mode;
+
+    #[test]
+    fn test_cues_follow_the_position() {
+        let cues = parse(
+            "# intro
+             1:00    gain 2
+             0:00    preset radial
+             1:00    rotate on  # chorus
+            ",
+        ).unwrap();
+        assert_eq!(
+            cues[0],
+            Cue {
+                time: 0,
+                change: Change::Preset(Mode::Radial),
+            }
+        );
+        assert!(parse("0:00 preset nothing").is_err());
+
+        let mut player = CuePlayer::new(Show {
+            cues: cues,
+            timecode: Timecode::Position,
+        });
+        let second = 1_000_000_000;
+        assert_eq!(player.seek(None), vec![]);
+        assert_eq!(player.seek(Some(0)), vec![Change::Preset(Mode::Radial)]);
+        assert_eq!(player.seek(Some(59 * second)), vec![]);
+        assert_eq!(
+            player.seek(Some(61 * second)),
+            vec![Change::Gain(2.0), Change::Rotate(true)]
+        );
+        // going back applies everything up to there again
+        assert_eq!(player.seek(Some(30 * second)), vec![Change::Preset(Mode::Radial)]);
+    }
+
+    #[test]
+    fn test_mtc_decoder() {
+        let mut decoder = MtcDecoder::new();
+        // 01:02:03, frame 4 at 25 fps as a full frame
+        let full_frame = [0xf0, 0x7f, 0x7f, 0x01, 0x01, 0x21, 0x02, 0x03, 0x04, 0xf7];
+        let times: Vec<_> = full_frame.iter().filter_map(|&b| decoder.push(b)).collect();
+        assert_eq!(times, vec![3723_160_000_000]);
+
+        // 00:00:10, frame 0 at 25 fps as quarter frames, with a clock in between
+        let pieces = [0x00, 0x10, 0x2a, 0x30, 0x40, 0x50, 0x60, 0x72];
+        let mut times = vec![];
+        for &piece in pieces.iter() {
+            times.extend(decoder.push(0xf1));
+            times.extend(decoder.push(0xf8));
+            times.extend(decoder.push(piece));
+        }
+        assert_eq!(times, vec![10_080_000_000]);
+    }
+
+    // the bits of a linear timecode frame
+    fn ltc_frame(hours: u8, minutes: u8, seconds: u8, frames: u8) -> Vec<bool> {
+        let mut bits = vec![false; LTC_BITS];
+        {
+            let mut digit = |start: usize, count: usize, value: u8| for i in 0..count {
+                bits[start + i] = value >> i & 1 == 1;
+            };
+            digit(0, 4, frames % 10);
+            digit(8, 2, frames / 10);
+            digit(16, 4, seconds % 10);
+            digit(24, 3, seconds / 10);
+            digit(32, 4, minutes % 10);
+            digit(40, 3, minutes / 10);
+            digit(48, 4, hours % 10);
+            digit(56, 2, hours / 10);
+        }
+        bits[LTC_BITS - LTC_SYNC.len()..].copy_from_slice(&LTC_SYNC);
+        bits
+    }
+
+    // biphase mark coded bits after some silence
+    fn ltc_signal(bits: &[bool], samples_per_bit: f32) -> Vec<f32> {
+        let mut signal = vec![0.0; 100];
+        let mut level = 0.5;
+        let at = |position: f32| 100 + position.round() as usize;
+        for (i, &bit) in bits.iter().enumerate() {
+            let (start, middle, end) = (
+                at(i as f32 * samples_per_bit),
+                at((i as f32 + 0.5) * samples_per_bit),
+                at((i + 1) as f32 * samples_per_bit),
+            );
+            level = -level;
+            signal.resize(middle, level);
+            if bit {
+                level = -level;
+            }
+            signal.resize(end, level);
+            assert!(start <= middle);
+        }
+        // the edge starting the next bit ends the last one
+        let end = signal.len() + samples_per_bit as usize;
+        signal.resize(end, -level);
+        signal
+    }
+
+    #[test]
+    fn test_ltc_decoder() {
+        // 00:00:10, frames 5 to 7 at 25 fps in chunks like the ones of the pipeline
+        let bits: Vec<bool> = (5..8).flat_map(|frame| ltc_frame(0, 0, 10, frame)).collect();
+        let signal = ltc_signal(&bits, 44100.0 / (25 * LTC_BITS) as f32);
+        let mut decoder = LtcDecoder::new(44100);
+        let times: Vec<u64> = signal
+            .chunks(822)
+            .filter_map(|chunk| decoder.push(chunk))
+            .collect();
+        assert_eq!(times.last(), Some(&10_320_000_000));
+
+        // 01:02:03, frame 29 at 30 fps
+        let bits: Vec<bool> = (0..3).flat_map(|_| ltc_frame(1, 2, 3, 29)).collect();
+        let signal = ltc_signal(&bits, 44100.0 / (30 * LTC_BITS) as f32);
+        let mut decoder = LtcDecoder::new(44100);
+        assert_eq!(decoder.push(&signal), Some(3724_000_000_000));
+    }
+}
@@ -0,0 +1,219 @@
+// Spectral and temporal descriptors of the most recent audio.
+//
+// The spectral ones are computed from the magnitude spectrum of a SimpleDecoder fft over
+// the newest samples, the temporal ones directly from the samples of a frame.
+
+use std::fs::File;
+use std::io::Write;
+
+use broadcast;
+use frame::SpectrumFrame;
+use simple_decoder::SimpleDecoder;
+use window;
+
+// share of the spectral energy below the rolloff frequency
+const ROLLOFF: f32 = 0.85;
+const EPSILON: f32 = 1e-12;
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Features {
+    // center of mass of the spectrum in Hz
+    pub centroid: f32,
+    // standard deviation of the spectrum around the centroid in Hz
+    pub spread: f32,
+    // frequency below which ROLLOFF of the energy is located in Hz
+    pub rolloff: f32,
+    // geometric divided by arithmetic mean of the power spectrum, 0 (tonal) to 1 (noise)
+    pub flatness: f32,
+    // sign changes per sample
+    pub zero_crossing_rate: f32,
+    pub rms: f32,
+    // peak divided by rms
+    pub crest: f32,
+    // A-weighted level in dBFS
+    pub loudness: f32,
+}
+
+impl Features {
+    // the descriptors by name, used for the network sinks and exports
+    pub fn named(&self) -> Vec<(&'static str, f32)> {
+        vec![
+            ("centroid", self.centroid),
+            ("spread", self.spread),
+            ("rolloff", self.rolloff),
+            ("flatness", self.flatness),
+            ("zero_crossing_rate", self.zero_crossing_rate),
+            ("rms", self.rms),
+            ("crest", self.crest),
+            ("loudness", self.loudness),
+        ]
+    }
+}
+
+// relative gain of the A-weighting curve at `f` Hz
+fn a_weighting(f: f32) -> f32 {
+    let f2 = f * f;
+    let numerator = 12194.0f32.powi(2) * f2 * f2;
+    let denominator = (f2 + 20.6f32.powi(2)) *
+        ((f2 + 107.7f32.powi(2)) * (f2 + 737.9f32.powi(2))).sqrt() *
+        (f2 + 12194.0f32.powi(2));
+    // normalize to 0dB at 1kHz
+    numerator / denominator * 1.2589
+}
+
+pub struct FeatureExtractor {
+    decoder: SimpleDecoder,
+    samples: Vec<f32>,
+    weights: Vec<f32>,
+}
+
+impl FeatureExtractor {
+    pub fn new(sample_count: usize, sample_rate: usize) -> Self {
+        let weights = (0..sample_count / 2)
+            .map(|i| a_weighting(i as f32 * sample_rate as f32 / sample_count as f32))
+            .collect();
+        FeatureExtractor {
+            decoder: SimpleDecoder::new(sample_count, sample_rate),
+            samples: vec![0.0; sample_count],
+            weights: weights,
+        }
+    }
+
+    pub fn analyze(&mut self, samples: &[f32]) -> Features {
+        window::slide(&mut self.samples, samples);
+
+        let mut features = Features::default();
+
+        // temporal descriptors of the fresh samples
+        if !samples.is_empty() {
+            let power = samples.iter().map(|v| v * v).sum::<f32>() / samples.len() as f32;
+            let peak = samples.iter().map(|v| v.abs()).fold(0.0, f32::max);
+            let crossings = samples
+                .windows(2)
+                .filter(|w| (w[0] >= 0.0) != (w[1] >= 0.0))
+                .count();
+            features.rms = power.sqrt();
+            features.crest = peak / (features.rms + EPSILON);
+            features.zero_crossing_rate = crossings as f32 / usize::max(samples.len() - 1, 1) as f32;
+        }
+
+        // spectral descriptors of the newest window
+        let magnitudes = self.decoder.magnitudes(&self.samples);
+        let resolution = self.decoder.sample_rate as f32 / self.decoder.sample_count as f32;
+        let freq = |i: usize| i as f32 * resolution;
+
+        let total: f32 = magnitudes.iter().sum();
+        if total <= EPSILON {
+            features.loudness = 20.0 * EPSILON.log10();
+            return features;
+        }
+
+        features.centroid = magnitudes
+            .iter()
+            .enumerate()
+            .map(|(i, m)| freq(i) * m)
+            .sum::<f32>() / total;
+        features.spread = (magnitudes
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (freq(i) - features.centroid).powi(2) * m)
+            .sum::<f32>() / total)
+            .sqrt();
+
+        let power: Vec<f32> = magnitudes.iter().map(|m| m * m).collect();
+        let total_power: f32 = power.iter().sum();
+        let mut cumulative = 0.0;
+        for (i, p) in power.iter().enumerate() {
+            cumulative += p;
+            if cumulative >= ROLLOFF * total_power {
+                features.rolloff = freq(i);
+                break;
+            }
+        }
+
+        let log_mean = power.iter().map(|p| (p + EPSILON).ln()).sum::<f32>() / power.len() as f32;
+        let mean = total_power / power.len() as f32;
+        features.flatness = log_mean.exp() / (mean + EPSILON);
+
+        // mean square of the weighted signal by parseval's theorem, the energy is split
+        // between both halves of the spectrum and the hann window has a mean square of 3/8
+        let n = self.decoder.sample_count as f32;
+        let weighted_power: f32 = power
+            .iter()
+            .zip(self.weights.iter())
+            .map(|(p, w)| p * w * w)
+            .sum();
+        let mean_square = 2.0 * weighted_power / (n * n * 3.0 / 8.0);
+        // relative to a full scale sine which has a mean square of 1/2
+        features.loudness = 10.0 * (mean_square * 2.0 + EPSILON).log10();
+
+        features
+    }
+}
+
+// write the features of every frame as comma separated values
+pub fn export(path: String, mut rx: broadcast::Receiver<SpectrumFrame>) {
+    let file = match File::create(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Failed to create feature export {}: {}", path, e);
+            return;
+        }
+    };
+    let mut out = file;
+
+    let header: Vec<&str> = Features::default().named().iter().map(|&(n, _)| n).collect();
+    if let Err(e) = writeln!(out, "sequence,pts,{}", header.join(",")) {
+        println!("Failed to write feature export {}: {}", path, e);
+        return;
+    }
+
+    while let Ok(frame) = rx.recv() {
+        let values: Vec<String> = frame
+            .features
+            .named()
+            .iter()
+            .map(|&(_, v)| v.to_string())
+            .collect();
+        let pts = frame.pts.map(|pts| pts.to_string()).unwrap_or_default();
+        if let Err(e) = writeln!(out, "{},{},{}", frame.sequence, pts, values.join(",")) {
+            println!("Failed to write feature export {}: {}", path, e);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FeatureExtractor;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn test_sine_is_tonal() {
+        let samples: Vec<f32> = (0..2048)
+            .map(|i| (2.0 * PI * 1000.0 * i as f32 / 44100.0).sin())
+            .collect();
+        let features = FeatureExtractor::new(2048, 44100).analyze(&samples);
+        assert!((features.centroid - 1000.0).abs() < 50.0);
+        assert!((features.rolloff - 1000.0).abs() < 50.0);
+        assert!(features.flatness < 0.1);
+        // two sign changes per period
+        assert!((features.zero_crossing_rate - 2000.0 / 44100.0).abs() < 0.002);
+    }
+
+    #[test]
+    fn test_noise_is_flat() {
+        // a linear congruential generator is white enough
+        let mut state: u32 = 1;
+        let samples: Vec<f32> = (0..2048)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                state as f32 / u32::max_value() as f32 * 2.0 - 1.0
+            })
+            .collect();
+        let features = FeatureExtractor::new(2048, 44100).analyze(&samples);
+        assert!(features.flatness > 0.4);
+        assert!(features.centroid > 5000.0);
+        assert!(features.zero_crossing_rate > 0.3);
+    }
+}
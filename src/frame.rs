@@ -0,0 +1,58 @@
+use std::time::Instant;
+
+use chroma::{Chord, Key};
+use features::Features;
+use gst::Stereo;
+use loudness::Loudness;
+use pitch::Pitch;
+
+// a single analysis result as it is passed between the stages and handed to the sinks, the
+// serialized form leaves out the raw samples and is used to record frames
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpectrumFrame {
+    // increases by one for every analyzed chunk of samples
+    pub sequence: u64,
+    // presentation timestamp of the underlying audio in nanoseconds
+    pub pts: Option<u64>,
+    // the moment the underlying audio was captured
+    #[serde(skip, default = "Instant::now")]
+    pub captured: Instant,
+    pub sample_rate: usize,
+    // sizes of the ffts that produced fresh values for this frame, the other bins are cached
+    pub fft_sizes: Vec<usize>,
+    // center frequency of every bin in Hz
+    pub freqs: Vec<f32>,
+    // normalized magnitude of every bin
+    pub bins: Vec<f32>,
+    // factor the automatic gain control scaled the bins by
+    pub gain: f32,
+    // level of the raw samples
+    pub rms: f32,
+    pub peak: f32,
+    // a beat was detected within the samples of this frame
+    pub beat: bool,
+    // fundamental of the dominant tone, `None` for silence
+    pub pitch: Option<Pitch>,
+    // energy per pitch class starting at C, normalized to a maximum of 1
+    pub chroma: Vec<f32>,
+    // running estimations of the key of the song and the current chord
+    pub key: Option<Key>,
+    pub chord: Option<Chord>,
+    // spectral and temporal descriptors of the newest samples
+    pub features: Features,
+    // EBU R128 loudness of the raw samples
+    pub loudness: Loudness,
+    // the raw samples this frame was computed from
+    #[serde(skip)]
+    pub samples: Vec<f32>,
+    // the separate channels of the samples when the input is stereo
+    #[serde(skip)]
+    pub stereo: Option<Stereo>,
+}
+
+impl SpectrumFrame {
+    // keep the beat of a frame a rate limited sink skips, so it shows up with the next one
+    pub fn latch_beat(skipped: &SpectrumFrame, next: &mut SpectrumFrame) {
+        next.beat |= skipped.beat;
+    }
+}
@@ -7,17 +7,71 @@ use gstreamer::ElementExt;
 use std::sync::mpsc::Sender;
 use failure::Error;
 use std;
+use std::time::{Duration, Instant};
 use byte_slice_cast::*;
 
-pub fn create_pipeline(tx: Sender<Vec<f32>>) -> Result<gstreamer::Pipeline, Error> {
+// the separate channels of stereo input
+#[derive(Clone, Debug)]
+pub struct Stereo {
+    pub left: Vec<f32>,
+    pub right: Vec<f32>,
+}
+
+pub struct Samples {
+    // presentation timestamp of the first sample in nanoseconds
+    pub pts: Option<u64>,
+    // the moment the first sample was recorded, used to measure latency downstream
+    pub captured: Instant,
+    // mono, stereo input is mixed down
+    pub data: Vec<f32>,
+    pub stereo: Option<Stereo>,
+}
+
+// split interleaved samples into the mono mix and, for two channels, the separate channels
+pub fn deinterleave(samples: &[f32], channels: usize) -> (Vec<f32>, Option<Stereo>) {
+    if channels != 2 {
+        return (Vec::from(samples), None);
+    }
+    let left: Vec<f32> = samples.chunks(2).map(|s| s[0]).collect();
+    let right: Vec<f32> = samples.chunks(2).map(|s| s[s.len() - 1]).collect();
+    let mono = left.iter().zip(right.iter()).map(|(l, r)| (l + r) / 2.0).collect();
+    (
+        mono,
+        Some(Stereo {
+            left: left,
+            right: right,
+        }),
+    )
+}
+
+// translate the buffer timestamp into a wall clock instant by looking at how far the
+// pipeline running time has advanced since the buffer was captured
+fn capture_instant(appsink: &gstreamer_app::AppSink, pts: Option<u64>) -> Instant {
+    let now = Instant::now();
+    let running_time = match (appsink.get_clock(), appsink.get_base_time().nseconds()) {
+        (Some(clock), Some(base_time)) => clock.get_time().nseconds().map(|t| t - base_time),
+        _ => None,
+    };
+    match (running_time, pts) {
+        (Some(running_time), Some(pts)) if running_time > pts => {
+            now - Duration::from_nanos(running_time - pts)
+        }
+        _ => now,
+    }
+}
+
+pub fn create_pipeline(tx: Sender<Samples>, channels: usize) -> Result<gstreamer::Pipeline, Error> {
     gstreamer::init()?;
 
-    let gs = match gstreamer::parse_launch(
-        "pulsesrc blocksize=3288 !
+    // the same number of samples per channel and buffer regardless of the channel count
+    let gs = match gstreamer::parse_launch(&format!(
+        "pulsesrc blocksize={} !
          appsink name=sink max-buffers=1 emit-signals=True
-         caps=audio/x-raw,format=F32LE,channels=1,rate=44100
+         caps=audio/x-raw,format=F32LE,channels={},rate=44100
          ",
-    ) {
+        3288 * channels,
+        channels
+    )) {
         Ok(gs) => gs,
         Err(e) => {
             println!("Failed to create pipeline: {:}", e);
@@ -94,7 +148,14 @@ pub fn create_pipeline(tx: Sender<Vec<f32>>) -> Result<gstreamer::Pipeline, Erro
                 //        f * f
                 //    })
                 //    .sum();
-                tx.send(Vec::from(samples)).unwrap();
+                let pts = buffer.get_pts().nseconds();
+                let (data, stereo) = deinterleave(samples, channels);
+                tx.send(Samples {
+                    pts: pts,
+                    captured: capture_instant(appsink, pts),
+                    data: data,
+                    stereo: stereo,
+                }).unwrap();
 
                 gstreamer::FlowReturn::Ok
             })
@@ -104,6 +165,84 @@ pub fn create_pipeline(tx: Sender<Vec<f32>>) -> Result<gstreamer::Pipeline, Erro
     Ok(pipeline)
 }
 
+// quote a value for a launch description
+pub fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// decodes an audio file as fast as possible instead of following the clock
+pub struct FileDecoder {
+    pipeline: gstreamer::Pipeline,
+    appsink: gstreamer_app::AppSink,
+    channels: usize,
+}
+
+impl FileDecoder {
+    pub fn open(path: &str, channels: usize) -> Result<FileDecoder, Error> {
+        gstreamer::init()?;
+
+        let pipeline = gstreamer::parse_launch(&format!(
+            "filesrc location={} ! decodebin ! audioconvert ! audioresample !
+             appsink name=sink sync=false
+             caps=audio/x-raw,format=F32LE,channels={},rate=44100
+             ",
+            quote(path),
+            channels
+        ))?
+            .dynamic_cast::<gstreamer::Pipeline>()
+            .expect("a pipeline to be created by the launch command");
+
+        let appsink = pipeline
+            .get_by_name("sink")
+            .expect("The sink must exist")
+            .dynamic_cast::<gstreamer_app::AppSink>()
+            .expect("An AppSink instance");
+
+        pipeline.set_state(gstreamer::State::Playing).into_result()?;
+
+        Ok(FileDecoder {
+            pipeline: pipeline,
+            appsink: appsink,
+            channels: channels,
+        })
+    }
+
+    // the error that ended the decoding early, if any
+    pub fn error(&self) -> Option<String> {
+        let bus = self.pipeline.get_bus()?;
+        while let Some(msg) = bus.pop() {
+            if let gstreamer::MessageView::Error(err) = msg.view() {
+                return Some(err.get_error().to_string());
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for FileDecoder {
+    type Item = Samples;
+
+    fn next(&mut self) -> Option<Samples> {
+        let sample = self.appsink.pull_sample()?;
+        let buffer = sample.get_buffer()?;
+        let map = buffer.map_readable()?;
+        let samples = map.as_slice().as_slice_of::<f32>().ok()?;
+        let (data, stereo) = deinterleave(samples, self.channels);
+        Some(Samples {
+            pts: buffer.get_pts().nseconds(),
+            captured: Instant::now(),
+            data: data,
+            stereo: stereo,
+        })
+    }
+}
+
+impl Drop for FileDecoder {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gstreamer::State::Null);
+    }
+}
+
 pub fn gst_loop(pipeline: gstreamer::Pipeline) -> Result<(), Error> {
     pipeline.set_state(gstreamer::State::Playing).into_result()?;
 
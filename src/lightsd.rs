@@ -1,9 +1,52 @@
 use std::net::UdpSocket;
-use std::sync::mpsc::{Receiver, channel};
+use std::str::FromStr;
 use std::thread::spawn;
 
+use broadcast;
+use control::Watcher;
+use cues::{Change, CuePlayer, Show};
+use frame::SpectrumFrame;
+
 use byteorder::{LittleEndian, WriteBytesExt};
 
+// level of the pulse effect for full scale rms
+const PULSE_GAIN: f32 = 4.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Effect {
+    // the bins spread over the strip
+    Spectrum,
+    // like the spectrum with the palette rotated a twelfth per semitone of the key
+    Key,
+    // the whole strip in the color of the key, following the level and flashing on beats
+    Pulse,
+    Off,
+}
+
+impl Effect {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Effect::Spectrum => "spectrum",
+            Effect::Key => "key",
+            Effect::Pulse => "pulse",
+            Effect::Off => "off",
+        }
+    }
+}
+
+impl FromStr for Effect {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "spectrum" => Ok(Effect::Spectrum),
+            "key" => Ok(Effect::Key),
+            "pulse" => Ok(Effect::Pulse),
+            "off" => Ok(Effect::Off),
+            _ => Err(()),
+        }
+    }
+}
+
 fn encode(data: Vec<(f32, f32, f32)>) -> Vec<u8> {
     let mut wrt = vec![];
 
@@ -16,7 +59,7 @@ fn encode(data: Vec<(f32, f32, f32)>) -> Vec<u8> {
 }
 
 
-fn send(target: &str, rx: Receiver<Vec<(f32, f32, f32)>>) {
+fn send(target: &str, mut rx: broadcast::Receiver<Vec<(f32, f32, f32)>>) {
     let sock = UdpSocket::bind("[::]:12345").unwrap();
 
     while let Ok(d) = rx.recv() {
@@ -26,22 +69,69 @@ fn send(target: &str, rx: Receiver<Vec<(f32, f32, f32)>>) {
 }
 
 
-pub fn leds(target: &'static str, sample_rx: Receiver<Vec<f32>>) {
-    let (tx, rx) = channel();
+pub fn leds(
+    target: String,
+    show: Option<Show>,
+    mut control: Watcher,
+    mut sample_rx: broadcast::Receiver<SpectrumFrame>,
+) {
+    let tx = broadcast::channel();
+    let rx = tx.subscribe("leds-udp", None);
     let led_count = 2200;
-    spawn(move || send(target, rx));
+    spawn(move || send(&target, rx));
+    let mut cues = show.map(CuePlayer::new);
+    // set by cues and the control API, the value is scaled by the brightness and the hue
+    // shifted in degrees
+    let parameters = control.parameters();
+    let mut effect = parameters.leds_effect;
+    let mut brightness = parameters.leds_brightness;
+    let mut hue = parameters.leds_hue;
     while let Ok(d) = sample_rx.recv() {
-        // some magic!
-        let buf: Vec<(f32, f32, f32)> = d.iter()
-            .map(|v| ((v * 180.).abs(), 1.0, *v))
-            .map(|(h, s, v)| {
-                ((180.0 + h), f32::max(s, 0.4), f32::max(v, 0.4))
-            })
-            .collect();
+        let mut changes = control.changes();
+        if let Some(ref mut cues) = cues {
+            for change in cues.advance(d.pts) {
+                control.note(&change);
+                changes.push(change);
+            }
+        }
+        for change in changes {
+            match change {
+                Change::LedEffect(e) => effect = e,
+                Change::LedBrightness(b) => brightness = f32::max(b, 0.0),
+                Change::LedHue(h) => hue = h,
+                _ => (),
+            }
+        }
+        // a twelfth of the hue circle per semitone of the key
+        let key_hue = d.key.as_ref().map(|k| k.tonic as f32 * 30.0).unwrap_or(0.0);
+        // only the key effect follows the key with the spectrum
+        let spectrum_hue = if effect == Effect::Key { key_hue } else { 0.0 };
+        let buf: Vec<(f32, f32, f32)> = match effect {
+            // some magic!
+            Effect::Spectrum | Effect::Key => d.bins.iter()
+                .map(|v| ((v * 180.).abs(), 1.0, *v))
+                .map(|(h, s, v)| {
+                    (
+                        ((180.0 + h + spectrum_hue + hue) % 360.0 + 360.0) % 360.0,
+                        f32::max(s, 0.4),
+                        f32::max(v, 0.4) * brightness,
+                    )
+                })
+                .collect(),
+            Effect::Pulse => {
+                let level = if d.beat {
+                    1.0
+                } else {
+                    f32::min(d.rms * PULSE_GAIN, 1.0)
+                };
+                vec![(((key_hue + hue) % 360.0 + 360.0) % 360.0, 1.0, level * brightness)]
+            }
+            Effect::Off => vec![(0.0, 0.0, 0.0)],
+        };
         let mut b = vec![];
         while b.len() < led_count {
             b.extend(&buf);
         }
-        tx.send(b).unwrap();
+        tx.send(b);
     }
 }
@@ -0,0 +1,415 @@
+// Loudness metering following EBU R128 / ITU-R BS.1770.
+//
+// The samples are K-weighted (a high shelf modelling the head followed by a high pass) and
+// their mean square is collected in blocks of 100ms, summed over the channels. Momentary
+// loudness spans the last 400ms, short-term loudness the last 3s. The integrated loudness
+// gates 400ms blocks absolutely at -70 LUFS and relatively at 10 LU below their mean, the
+// loudness range takes the spread between the 10th and 95th percentile of the short-term
+// loudness gated at -20 LU. Like libebur128 the blocks are counted in a histogram of 0.1 LU
+// steps so a meter running for hours needs no more memory or time than a fresh one.
+// True peak is measured on a 4x oversampled signal.
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// 100ms sub blocks, momentary and short-term loudness are made up of 4 and 30 of them
+const BLOCKS_PER_SECOND: usize = 10;
+const MOMENTARY_BLOCKS: usize = 4;
+const SHORT_TERM_BLOCKS: usize = 30;
+
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+
+// 0.1 LU steps from the absolute gate up to +30 LUFS
+const HISTOGRAM_STEP: f64 = 0.1;
+const HISTOGRAM_BINS: usize = 1000;
+
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+// reported for silence instead of -inf
+pub const SILENCE: f32 = -144.0;
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Loudness {
+    // LUFS over the last 400ms
+    pub momentary: f32,
+    // LUFS over the last 3s
+    pub short_term: f32,
+    // gated LUFS since the meter was started
+    pub integrated: f32,
+    // LU between the quiet and loud parts
+    pub range: f32,
+    // maximum true peak since the meter was started in dBTP
+    pub true_peak: f32,
+}
+
+impl Default for Loudness {
+    fn default() -> Self {
+        Loudness {
+            momentary: SILENCE,
+            short_term: SILENCE,
+            integrated: SILENCE,
+            range: 0.0,
+            true_peak: SILENCE,
+        }
+    }
+}
+
+impl Loudness {
+    // the values by name, used for the network sinks
+    pub fn named(&self) -> Vec<(&'static str, f32)> {
+        vec![
+            ("momentary", self.momentary),
+            ("short_term", self.short_term),
+            ("integrated", self.integrated),
+            ("range", self.range),
+            ("true_peak", self.true_peak),
+        ]
+    }
+}
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad {
+            b: b,
+            a: a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1] -
+            self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+// the K-weighting filters of BS.1770 for an arbitrary sample rate
+fn k_weighting(sample_rate: f64) -> (Biquad, Biquad) {
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / sample_rate).tan();
+    let vh = 10.0f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / sample_rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    (shelf, high_pass)
+}
+
+fn to_lufs(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+fn from_lufs(lufs: f64) -> f64 {
+    10.0f64.powf((lufs + 0.691) / 10.0)
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+// number of blocks per loudness, blocks below the absolute gate are not counted
+struct Histogram {
+    counts: Vec<u64>,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            counts: vec![0; HISTOGRAM_BINS],
+        }
+    }
+
+    fn add(&mut self, mean_square: f64) {
+        let lufs = to_lufs(mean_square);
+        // silence is -inf
+        if lufs.is_nan() || lufs <= ABSOLUTE_GATE {
+            return;
+        }
+        let bin = ((lufs - ABSOLUTE_GATE) / HISTOGRAM_STEP) as usize;
+        self.counts[usize::min(bin, HISTOGRAM_BINS - 1)] += 1;
+    }
+
+    // the loudness at the center of a bin
+    fn lufs(bin: usize) -> f64 {
+        ABSOLUTE_GATE + (bin as f64 + 0.5) * HISTOGRAM_STEP
+    }
+
+    // the first bin louder than `threshold`
+    fn first_above(threshold: f64) -> usize {
+        if threshold <= ABSOLUTE_GATE {
+            return 0;
+        }
+        usize::min(
+            ((threshold - ABSOLUTE_GATE) / HISTOGRAM_STEP).round() as usize,
+            HISTOGRAM_BINS,
+        )
+    }
+
+    // the mean square of the blocks from bin `first` on
+    fn mean(&self, first: usize) -> Option<f64> {
+        let (count, sum) = self.counts[first..]
+            .iter()
+            .enumerate()
+            .fold((0, 0.0), |(count, sum), (i, &c)| {
+                (count + c, sum + c as f64 * from_lufs(Histogram::lufs(first + i)))
+            });
+        if count == 0 {
+            None
+        } else {
+            Some(sum / count as f64)
+        }
+    }
+
+    // the mean loudness of the blocks louder than `relative_gate` below their mean
+    fn gated_loudness(&self, relative_gate: f64) -> Option<f64> {
+        let threshold = to_lufs(self.mean(0)?) + relative_gate;
+        self.mean(Histogram::first_above(threshold)).map(to_lufs)
+    }
+
+    // the loudness of the block at `p` of the `total` blocks from bin `first` on
+    fn percentile(&self, first: usize, total: u64, p: f64) -> f64 {
+        let index = ((total - 1) as f64 * p).round() as u64;
+        let mut seen = 0;
+        for (i, &c) in self.counts[first..].iter().enumerate() {
+            seen += c;
+            if seen > index {
+                return Histogram::lufs(first + i);
+            }
+        }
+        Histogram::lufs(HISTOGRAM_BINS - 1)
+    }
+
+    // the spread of the blocks louder than `relative_gate` below their mean
+    fn range(&self, relative_gate: f64) -> Option<f64> {
+        let threshold = to_lufs(self.mean(0)?) + relative_gate;
+        let first = Histogram::first_above(threshold);
+        let total: u64 = self.counts[first..].iter().sum();
+        if total == 0 {
+            return None;
+        }
+        Some(self.percentile(first, total, 0.95) - self.percentile(first, total, 0.10))
+    }
+}
+
+// windowed sinc interpolation filter, split into one set of taps per output phase
+fn interpolation_filter() -> Vec<Vec<f64>> {
+    let length = OVERSAMPLING * TAPS_PER_PHASE;
+    let center = (length - 1) as f64 / 2.0;
+    let taps: Vec<f64> = (0..length)
+        .map(|n| {
+            let t = (n as f64 - center) / OVERSAMPLING as f64;
+            let sinc = if t == 0.0 {
+                1.0
+            } else {
+                (PI * t).sin() / (PI * t)
+            };
+            let window = 0.5 - 0.5 * (2.0 * PI * n as f64 / (length - 1) as f64).cos();
+            sinc * window
+        })
+        .collect();
+    (0..OVERSAMPLING)
+        .map(|phase| {
+            (0..TAPS_PER_PHASE)
+                .map(|k| taps[k * OVERSAMPLING + phase])
+                .collect()
+        })
+        .collect()
+}
+
+// the filters of a single channel
+struct Channel {
+    shelf: Biquad,
+    high_pass: Biquad,
+    // most recent samples for the oversampling, the newest first
+    history: VecDeque<f64>,
+}
+
+impl Channel {
+    fn new(sample_rate: usize) -> Self {
+        let (shelf, high_pass) = k_weighting(sample_rate as f64);
+        Channel {
+            shelf: shelf,
+            high_pass: high_pass,
+            history: VecDeque::from(vec![0.0; TAPS_PER_PHASE]),
+        }
+    }
+
+    // the K-weighted sample
+    fn filter(&mut self, x: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(x))
+    }
+
+    // the largest magnitude of the oversampled signal up to `x`
+    fn true_peak(&mut self, x: f64, phases: &[Vec<f64>]) -> f64 {
+        self.history.pop_back();
+        self.history.push_front(x);
+        let history = &self.history;
+        phases
+            .iter()
+            .map(|taps| {
+                taps.iter()
+                    .zip(history.iter())
+                    .map(|(h, x)| h * x)
+                    .sum::<f64>()
+                    .abs()
+            })
+            .fold(0.0, f64::max)
+    }
+}
+
+pub struct LoudnessMeter {
+    sample_rate: usize,
+    channels: Vec<Channel>,
+    block_size: usize,
+    // sum of squares of the block that is currently filled
+    block_sum: f64,
+    block_fill: usize,
+    // mean squares of the most recent 100ms blocks
+    recent: VecDeque<f64>,
+    // all 400ms (momentary) and 3s (short-term) windows since the start
+    gating_blocks: Histogram,
+    short_term_blocks: Histogram,
+    phases: Vec<Vec<f64>>,
+    true_peak: f64,
+    current: Loudness,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: usize) -> Self {
+        LoudnessMeter {
+            sample_rate: sample_rate,
+            channels: vec![],
+            block_size: sample_rate / BLOCKS_PER_SECOND,
+            block_sum: 0.0,
+            block_fill: 0,
+            recent: VecDeque::with_capacity(SHORT_TERM_BLOCKS),
+            gating_blocks: Histogram::new(),
+            short_term_blocks: Histogram::new(),
+            phases: interpolation_filter(),
+            true_peak: 0.0,
+            current: Loudness::default(),
+        }
+    }
+
+    fn finish_block(&mut self) {
+        if self.recent.len() == SHORT_TERM_BLOCKS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(self.block_sum / self.block_size as f64);
+        self.block_sum = 0.0;
+        self.block_fill = 0;
+
+        let recent: Vec<f64> = self.recent.iter().cloned().collect();
+        let momentary = mean(&recent[recent.len().saturating_sub(MOMENTARY_BLOCKS)..]);
+        let short_term = mean(&recent);
+
+        // windows are only complete once enough blocks have been collected
+        if recent.len() >= MOMENTARY_BLOCKS {
+            self.gating_blocks.add(momentary);
+        }
+        if recent.len() >= SHORT_TERM_BLOCKS {
+            self.short_term_blocks.add(short_term);
+        }
+
+        let integrated = self.gating_blocks.gated_loudness(RELATIVE_GATE);
+        let range = self.short_term_blocks.range(RANGE_RELATIVE_GATE);
+
+        let clamp = |lufs: f64| if lufs.is_finite() {
+            f64::max(lufs, SILENCE as f64) as f32
+        } else {
+            SILENCE
+        };
+        self.current.momentary = clamp(to_lufs(momentary));
+        self.current.short_term = clamp(to_lufs(short_term));
+        self.current.integrated = integrated.map(clamp).unwrap_or(SILENCE);
+        self.current.range = range.unwrap_or(0.0) as f32;
+    }
+
+    // measure the samples of every channel, e.g. left and right
+    pub fn process(&mut self, channels: &[&[f32]]) -> Loudness {
+        while self.channels.len() < channels.len() {
+            self.channels.push(Channel::new(self.sample_rate));
+        }
+        let length = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+        for i in 0..length {
+            for (channel, samples) in self.channels.iter_mut().zip(channels.iter()) {
+                let x = samples[i] as f64;
+                let peak = channel.true_peak(x, &self.phases);
+                if peak > self.true_peak {
+                    self.true_peak = peak;
+                }
+
+                // the channels are weighted equally
+                let y = channel.filter(x);
+                self.block_sum += y * y;
+            }
+            self.block_fill += 1;
+            if self.block_fill == self.block_size {
+                self.finish_block();
+            }
+        }
+
+        self.current.true_peak = if self.true_peak > 0.0 {
+            f64::max(20.0 * self.true_peak.log10(), SILENCE as f64) as f32
+        } else {
+            SILENCE
+        };
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoudnessMeter;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn test_sine_loudness() {
+        // a 1kHz sine with an rms of -20dBFS reads -20 LUFS on a single channel
+        let sample_rate = 48000;
+        let amplitude = 0.1 * 2.0f32.sqrt();
+        let samples: Vec<f32> = (0..sample_rate * 5)
+            .map(|i| amplitude * (2.0 * PI * 1000.0 * i as f32 / sample_rate as f32).sin())
+            .collect();
+
+        let mut meter = LoudnessMeter::new(sample_rate);
+        let loudness = meter.process(&[&samples]);
+        assert!((loudness.momentary - -20.0).abs() < 0.2, "{:?}", loudness);
+        assert!((loudness.integrated - -20.0).abs() < 0.2, "{:?}", loudness);
+        assert!(loudness.range < 0.5, "{:?}", loudness);
+        assert!((loudness.true_peak - -17.0).abs() < 0.2, "{:?}", loudness);
+
+        // the power of both channels adds up
+        let mut meter = LoudnessMeter::new(sample_rate);
+        let loudness = meter.process(&[&samples, &samples]);
+        assert!((loudness.integrated - -17.0).abs() < 0.2, "{:?}", loudness);
+    }
+}
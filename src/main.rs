@@ -1,8 +1,11 @@
 //#![feature(slice_rotate)]
 extern crate apodize;
+extern crate base64;
 extern crate byte_slice_cast;
 extern crate byteorder;
 extern crate failure;
+#[macro_use]
+extern crate failure_derive;
 extern crate threadpool;
 extern crate num_cpus;
 extern crate glib;
@@ -11,45 +14,51 @@ extern crate glium;
 #[macro_use]
 extern crate gstreamer;
 extern crate gstreamer_app;
+extern crate libc;
 extern crate num;
 extern crate rustfft;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate sha1;
 
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{Sender, Receiver, channel};
 use std::thread::spawn;
-use threadpool::ThreadPool;
-use std::sync::Arc;
-use std::sync::Mutex;
 
 
+mod analysis;
+mod bands;
 mod beat;
+mod broadcast;
+mod chroma;
+mod config;
+mod control;
+mod cues;
 mod debug;
+mod features;
+mod frame;
 mod gst;
 mod lightsd;
+mod loudness;
+mod midi;
+mod osc;
+mod overlay;
+mod pitch;
 mod process;
+mod recording;
+mod render;
+mod scope;
+mod shaders;
+mod shadertoy;
 mod simple_decoder;
+mod terminal;
+mod text;
 mod visual;
 mod tcp;
-
-fn normalize(input: Vec<f32>, global_max: f32) -> (Vec<f32>, f32) {
-    let mut max = input.iter().cloned().fold(0.0, f32::max);
-    if max < 0.0 {
-        max = 1.0;
-    }
-
-    let mut global_max = global_max * 0.99;
-    if global_max < max {
-        global_max = max;
-    }
-    let out: Vec<f32> = input.iter()
-    .map(|v| v / global_max)
-   // .map(|v| v.log(10.0) / 2.5 + 1.0)
-   // .map(|v| if v < 0.0 { 0.0 } else { v })
-    .collect();
-
-    (out, global_max)
-
-}
+mod websocket;
+mod window;
 
 const SAMPLING_DURATION: u64 = 16; // in milliseconds
 fn process_loop(n: usize, rx: Receiver<Vec<f32>>, tx: Sender<Vec<f32>>) {
@@ -99,171 +108,268 @@ fn smoothing(rx: Receiver<Vec<f32>>, tx: Sender<Vec<f32>>) {
 }
 
 
-fn cloneing_receiver<T>(rx: Receiver<T>) -> (Receiver<T>, Receiver<T>)
-where
-    T: Clone + Send + 'static,
-{
-    let (tx1, rx1) = channel();
-    let (tx2, rx2) = channel();
+fn main() {
+    let config = match config::Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Invalid arguments: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let cue_list = match config.cues {
+        Some(ref path) => match cues::load(path) {
+            Ok(cue_list) => cue_list,
+            Err(e) => {
+                println!("Failed to load the cues of {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => vec![],
+    };
+
+    // render a video of an audio file instead of visualizing the live input
+    if let Some(input) = config.render_input.clone() {
+        let output = match (config.render_video.clone(), config.render_pipeline.clone()) {
+            (Some(path), _) => render::Output::Video(path),
+            (None, Some(pipeline)) => render::Output::Pipeline(pipeline),
+            (None, None) => render::Output::Frames(config.render_output.clone()),
+        };
+        let options = render::Options {
+            input: input,
+            output: output,
+            fps: config.render_fps,
+            width: config.render_size.0,
+            height: config.render_size.1,
+            channels: config.channels,
+            cues: cue_list,
+        };
+        if let Err(e) = render::render(config.visual_settings(), options) {
+            println!("Rendering failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    spawn(move || while let Ok(d) = rx.recv() {
-        tx1.send(d.clone()).unwrap();
-        tx2.send(d).unwrap();
-    });
+    let (raw_tx, raw_rx) = channel();
 
-    (rx1, rx2)
-}
+    // configure our gstreamer pipeline, unless a recording is replayed instead
+    let pipeline = match (&config.replay, &config.replay_frames) {
+        (&None, &None) => Some(
+            gst::create_pipeline(raw_tx.clone(), config.channels).expect("A pipline to be created"),
+        ),
+        _ => None,
+    };
+
+    let mut recorder = match config.record {
+        Some(ref path) => match recording::Recorder::create(path, config.channels) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                println!("Failed to record to {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let mut frame_recorder = match config.record_frames {
+        Some(ref path) => match recording::FrameRecorder::create(path) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                println!("Failed to record frames to {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // every sink gets the latest spectrum, slow sinks skip frames instead of queueing them
+    // but still see the beats of the skipped ones
+    let spectrum_tx = broadcast::channel_merging(frame::SpectrumFrame::latch_beat);
+
+    // the cues follow the frames, the MIDI timecode or the linear timecode decoded by the
+    // analysis thread
+    let ltc_time = Arc::new(Mutex::new(None));
+    let show = config.cues.as_ref().map(|_| {
+        let timecode = match (config.mtc_input.clone(), config.ltc_channel) {
+            (Some(path), _) => {
+                let time = Arc::new(Mutex::new(None));
+                let mtc_time = Arc::clone(&time);
+                spawn(move || cues::read_mtc(path, mtc_time));
+                cues::Timecode::Midi(time)
+            }
+            (None, Some(_)) => cues::Timecode::Linear(Arc::clone(&ltc_time)),
+            (None, None) => cues::Timecode::Position,
+        };
+        cues::Show {
+            cues: cue_list,
+            timecode: timecode,
+        }
+    });
 
-fn fanout_receiver<T>(rx: Receiver<T>, n: usize) -> Vec<Receiver<T>>
-where
-    T: Clone + Send + 'static + std::fmt::Debug,
-{
-    let mut tx_channels: Vec<Sender<T>> = Vec::new();
-    let mut rx_channels: Vec<Receiver<T>> = Vec::new();
-
-    for _ in 0..n {
-        let (tx, rx) = channel();
-        tx_channels.push(tx);
-        rx_channels.push(rx);
+    // live parameters, changed through the control server
+    let control = control::Control::new(control::Parameters {
+        window: analysis::DEFAULT_WINDOW,
+        agc: true,
+        agc_decay: analysis::AGC_DECAY,
+        preset: config.visual_modes[0],
+        gain: 1.0,
+        rotate: config.visual_rotate.is_some(),
+        overlay: config.overlay,
+        leds_effect: config.leds_effect,
+        leds_brightness: 1.0,
+        leds_hue: 0.0,
+    });
+    if let Some(address) = config.control.clone() {
+        let control = control.clone();
+        spawn(move || control::serve(address, control));
     }
 
-    spawn(move || while let Ok(d) = rx.recv() {
-        tx_channels
-            .iter()
-            .map(|tx| tx.send(d.clone()).unwrap())
-            .count();
+    let leds_rx = spectrum_tx.subscribe_delayed("leds", config.leds_fps, config.leds_delay);
+    let leds_target = config.leds_target.clone();
+    let leds_show = show.clone();
+    let leds_control = control.watch();
+    spawn(move || lightsd::leds(leds_target, leds_show, leds_control, leds_rx));
+
+    let visual_rx =
+        spectrum_tx.subscribe_delayed("visual", config.visual_fps, config.visual_delay);
+    let visual_settings = config.visual_settings();
+    let visual_modes = config.visual_modes.clone();
+    let visual_show = show.clone();
+    let visual_control = control.watch();
+    spawn(move || {
+        visual::visual(visual_settings, visual_modes, visual_show, visual_control, visual_rx)
     });
 
-    rx_channels
-}
-
-
-fn main() {
-    let (raw_tx, raw_rx) = channel();
-
-    // configure our gstreamer pipeline
-    let pipeline = gst::create_pipeline(raw_tx).expect("A pipline to be created");
-    let (leds_tx, leds_rx) = channel();
+    if let Some(address) = config.websocket.clone() {
+        let spectrum_tx = spectrum_tx.clone();
+        spawn(move || websocket::serve(address, spectrum_tx));
+    }
 
-    spawn(move || lightsd::leds("172.20.64.232:1337", leds_rx));
+    if let Some(target) = config.osc_target.clone() {
+        let osc_rx = spectrum_tx.subscribe("osc", config.osc_fps);
+        let prefix = config.osc_prefix.clone();
+        let band_count = config.osc_bands;
+        spawn(move || osc::osc(target, prefix, band_count, osc_rx));
+    }
 
-    let (out_tx, out_rx) = channel();
-    spawn(move || visual::visual(out_rx));
-    // spawn a thread that handles all the processing of data and passes processed data onwards
-    spawn(move || {
+    let midi = config.midi_output.clone().and_then(|path| {
+        let midi_rx = spectrum_tx.subscribe("midi", None);
+        let settings = midi::Settings {
+            channel: config.midi_channel - 1,
+            threshold: config.midi_threshold,
+            band_count: config.midi_bands,
+        };
+        midi::midi(path, settings, midi_rx)
+    });
 
-        const sample_rate: usize = 44100;
+    if let Some(path) = config.features_csv.clone() {
+        let features_rx = spectrum_tx.subscribe("features", None);
+        spawn(move || features::export(path, features_rx));
+    }
 
-        // create a thread pool to execute everything on
-        let pool = ThreadPool::new(num_cpus::get_physical() - 1);
+    if config.terminal {
+        let terminal_rx = spectrum_tx.subscribe("terminal", config.terminal_fps);
+        let rows = config.terminal_rows;
+        spawn(move || terminal::terminal(rows, terminal_rx));
+    }
 
-        // create all the fft processors
-        let range = 8..14;
-        let range_start = range.start;
-        let range_end = range.end;
+    if let Some(interval) = config.stats_interval {
+        let monitor = spectrum_tx.monitor();
+        spawn(move || broadcast::report(monitor, interval));
+    }
 
-        let processors: Vec<(usize, Arc<Mutex<process::Processor>>)> = range
-            .map(|k| {
-                (
-                    k,
-                    Arc::new(Mutex::new(process::Processor::new(k, sample_rate))),
-                )
-            })
-            .collect();
+    // the recorded frames take the place of the analysis
+    if let Some(path) = config.replay_frames.clone() {
+        if let Err(e) = recording::replay_frames(&path, config.replay_speed, &spectrum_tx) {
+            println!("Failed to replay frames of {}: {}", path, e);
+            std::process::exit(1);
+        }
+        if let Some(ref midi) = midi {
+            midi.stop();
+        }
+        return;
+    }
 
-        // cache the last result of an fft
-        // this enables us to to updates even if just one fft reported
-        // new values
-        let mut fft_cache: HashMap<usize, Vec<f32>> = HashMap::new();
+    let ltc_channel = if show.is_some() { config.ltc_channel } else { None };
 
-        // let (beat_tx, beat_rx) = channel();
+    // spawn a thread that handles all the processing of data and passes processed data onwards
+    let analysis = spawn(move || {
+        const sample_rate: usize = 44100;
 
-        let mut global_max = 0.0;
+        let mut analyzer =
+            analysis::Analyzer::new(analysis::window_range(analysis::DEFAULT_WINDOW), sample_rate);
+        let mut analysis_control = control.watch();
+        let mut analysis_cues = show.map(cues::CuePlayer::new);
+        let mut ltc = ltc_channel.map(|channel| (channel, cues::LtcDecoder::new(sample_rate)));
 
         // for each received sample frame
-        let mut frame_counter = 0;
- //       let mut now = std::time::Instant::now();
-        while let Ok(d) = raw_rx.recv() {
-            let (tx, rx) = channel();
-//            println!("Elapsed {} {} {}", "recv", now.elapsed().subsec_nanos() / 1000, d.len());
-            //let (loop_beat_tx, loop_beat_rx) = channel();
-            // pass it to the beat detector as task
-            //{
-            //    let beat_data = d.clone();
-            //    let btx = loop_beat_tx.clone();
-            //    let mut beat_detector = Arc::clone(&beat_detector);
-            //    pool.execute(move || {
-            //        let mut beat_detector = beat_detector.lock().expect("Scheduled beat detector more than once");
-            //        let d = beat_detector.analyze(&beat_data);
-            //        btx.send(d).expect("Result channel must be open");
-            //    });
-            //}
-
-            // feed it into our fft processs loop
-            //
-            processors
-                .iter()
-                .map(|(k, p)| (*k, Arc::clone(p)))
-                .map(|(k, p)| {
-                    let d = d.clone();
-                    let tx = tx.clone();
-                    pool.execute(move || {
-                        let mut p = p.lock().expect("Processor scheduled more than once");
-                        tx.send((k, p.process(d))).expect(
-                            "Result channel must be open",
-                        );
-                    });
-                })
-                .last();
-
-            // await all the ffts before continuing
-            let mut bins = vec![0.0; 7 * 12];
-            let cached_results = rx.into_iter()
-                .map(|(k, r)| {
-
-                    let r =
-                        r.map(|r| {
-                            fft_cache.insert(k, r.clone());
-                            r
-                        }).unwrap_or_else(
-                                || fft_cache.get(&k).unwrap_or(&vec![0.0; 7 * 12]).clone(),
-                            );
-                    (k, r)
-                })
-                .take(processors.len());
-            /*
-            let merged_bins = cached_results.fold(bins, |bins, (k, r)|{
-                bins.into_iter().zip(r.into_iter())
-                    .map(|(a, b)| a + b / factor).collect()
-            });
-*/
-            // k \in [8, 13] = range
-            let no_of_points = bins.len();
-            cached_results
-                .map(|(k, r)| {
-                    debug_assert!(no_of_points == r.len());
-                    let to = (range_end + 1 - k) * 12;
-                    let from = if k == range_end - 1 {
-                        0
-                    } else {
-                        (range_end - k) * 12
-                    };
-                    bins.splice(from..to, r.into_iter().skip(from).take(to - from));
-                })
-                .count();
-
-            let (merged_bins, max) = normalize(bins, global_max);
-            global_max = max;
-
-            // check if all the ffts did return results, if not pick the previous result of that
-            // fft (if available)
-            leds_tx.send(merged_bins.clone()).unwrap();
-            out_tx.send(merged_bins).unwrap();
+        while let Ok(samples) = raw_rx.recv() {
+            if let Some((channel, ref mut decoder)) = ltc {
+                let input = match samples.stereo {
+                    Some(ref stereo) if channel == 1 => &stereo.right,
+                    Some(ref stereo) => &stereo.left,
+                    None => &samples.data,
+                };
+                if let Some(time) = decoder.push(input) {
+                    *ltc_time.lock().unwrap() = Some(time);
+                }
+            }
+            let mut changes = analysis_control.changes();
+            if let Some(ref mut cues) = analysis_cues {
+                for change in cues.advance(samples.pts) {
+                    analysis_control.note(&change);
+                    changes.push(change);
+                }
+            }
+            for change in changes {
+                match change {
+                    cues::Change::Window(window) => {
+                        analyzer.set_range(analysis::window_range(window))
+                    }
+                    cues::Change::Agc(enabled) => analyzer.set_agc(enabled),
+                    cues::Change::AgcDecay(decay) => analyzer.set_agc_decay(decay),
+                    _ => (),
+                }
+            }
+
+            let failed = match recorder {
+                Some(ref mut recorder) => recorder.write(&samples).err(),
+                None => None,
+            };
+            if let Some(e) = failed {
+                println!("Stopped recording: {}", e);
+                recorder = None;
+            }
+            let frame = analyzer.analyze(samples);
+            let failed = match frame_recorder {
+                Some(ref mut recorder) => recorder.write(&frame).err(),
+                None => None,
+            };
+            if let Some(e) = failed {
+                println!("Stopped recording frames: {}", e);
+                frame_recorder = None;
+            }
+            spectrum_tx.send_at(frame.captured, frame);
         }
     });
 
     // this drives all the other tasks since we require new audio samples.
-    gst::gst_loop(pipeline).expect("Clean end.")
+    match (config.replay.clone(), pipeline) {
+        (Some(path), _) => {
+            if let Err(e) = recording::replay(&path, config.replay_speed, raw_tx) {
+                println!("Failed to replay {}: {}", path, e);
+                std::process::exit(1);
+            }
+            // let the analysis finish the last samples
+            analysis.join().unwrap();
+        }
+        (None, Some(pipeline)) => gst::gst_loop(pipeline).expect("Clean end."),
+        (None, None) => unreachable!(),
+    }
+    // don't leave notes hanging on the MIDI output
+    if let Some(ref midi) = midi {
+        midi.stop();
+    }
 }
 
 //fn old_main() {
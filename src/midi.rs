@@ -0,0 +1,357 @@
+// Raw MIDI output, e.g. to an ALSA raw MIDI device such as the ones created by snd-virmidi
+// (which also show up as sequencer ports) or a FIFO. The messages are written as they are
+// sent on the wire, not as a Standard MIDI File, so regular files are rejected.
+//
+// * semitones whose energy crosses the threshold trigger note-on/off events
+// * the energy of each band is sent as control change, starting at `FIRST_CC`
+// * a MIDI clock (24 pulses per quarter note) follows the tempo of the detected beats, it
+//   is stopped when no beat was detected for `STOP_AFTER_BEATS` beats
+// * once the sink stops the notes that are still on are released
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use bands;
+use beat::TempoEstimator;
+use broadcast;
+use frame::SpectrumFrame;
+use simple_decoder::PER_OCTAVE;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xb0;
+const CLOCK: u8 = 0xf8;
+const START: u8 = 0xfa;
+const STOP: u8 = 0xfc;
+
+// channel mode message, sent on exit in case a receiver missed a note-off
+const ALL_NOTES_OFF: u8 = 123;
+// general purpose controllers are 16 - 19 and 80 - 83, 20 - 31 are undefined
+const FIRST_CC: u8 = 20;
+const CLOCKS_PER_BEAT: u32 = 24;
+const STOP_AFTER_BEATS: u32 = 4;
+// notes are released once they drop below this fraction of the threshold
+const RELEASE_RATIO: f32 = 0.8;
+
+pub struct Settings {
+    // MIDI channel 0 - 15
+    pub channel: u8,
+    pub threshold: f32,
+    pub band_count: usize,
+}
+
+// the MIDI note number of the v-th semitone bin
+fn midi_note(v: usize) -> u8 {
+    // the first bin is three octaves below the kammerton A4 which is note 69
+    (69 - 3 * PER_OCTAVE + v) as u8
+}
+
+fn to_7bit(v: f32) -> u8 {
+    (v.max(0.0).min(1.0) * 127.0) as u8
+}
+
+// the notes and controllers of the frames
+struct Encoder {
+    settings: Settings,
+    notes: Vec<bool>,
+    controls: Vec<u8>,
+}
+
+impl Encoder {
+    fn new(settings: Settings) -> Encoder {
+        Encoder {
+            settings: settings,
+            notes: vec![],
+            controls: vec![],
+        }
+    }
+
+    fn encode(&mut self, bins: &[f32]) -> Vec<u8> {
+        let channel = self.settings.channel & 0x0f;
+        let threshold = self.settings.threshold;
+        let mut bytes = vec![];
+
+        self.notes.resize(bins.len(), false);
+        for (v, &energy) in bins.iter().enumerate() {
+            if !self.notes[v] && energy >= threshold {
+                self.notes[v] = true;
+                // a note-on of velocity 0 is a note-off
+                let velocity = u8::max(to_7bit(energy), 1);
+                bytes.extend(&[NOTE_ON | channel, midi_note(v), velocity]);
+            } else if self.notes[v] && energy < threshold * RELEASE_RATIO {
+                self.notes[v] = false;
+                bytes.extend(&[NOTE_OFF | channel, midi_note(v), 0]);
+            }
+        }
+
+        let band_values: Vec<u8> = bands::group(bins, self.settings.band_count)
+            .into_iter()
+            .map(to_7bit)
+            .collect();
+        self.controls.resize(band_values.len(), 128);
+        for (i, &value) in band_values.iter().enumerate() {
+            // only send controllers that changed
+            if self.controls[i] != value {
+                self.controls[i] = value;
+                bytes.extend(&[CONTROL_CHANGE | channel, FIRST_CC + i as u8, value]);
+            }
+        }
+        bytes
+    }
+
+    // note-offs for the notes that are on followed by all-notes-off
+    fn release(&mut self) -> Vec<u8> {
+        let channel = self.settings.channel & 0x0f;
+        let mut bytes = vec![];
+        for (v, on) in self.notes.iter_mut().enumerate() {
+            if *on {
+                *on = false;
+                bytes.extend(&[NOTE_OFF | channel, midi_note(v), 0]);
+            }
+        }
+        bytes.extend(&[CONTROL_CHANGE | channel, ALL_NOTES_OFF, 0]);
+        bytes
+    }
+}
+
+struct Tempo {
+    // time between two clock pulses
+    tick: Option<Duration>,
+    last_beat: Option<Instant>,
+}
+
+// the device shared by the frames and the clock
+struct Output {
+    file: Box<Write + Send>,
+    encoder: Encoder,
+    // a START has been sent and no STOP since
+    clock_running: bool,
+    // the sink has been stopped, nothing is written anymore
+    stopped: bool,
+}
+
+impl Output {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes).and_then(|_| self.file.flush())
+    }
+}
+
+// handle on a running MIDI output
+pub struct Sink {
+    output: Arc<Mutex<Output>>,
+}
+
+impl Sink {
+    // release the notes that are still on and stop the clock, called before exiting
+    pub fn stop(&self) {
+        let mut output = self.output.lock().unwrap();
+        if output.stopped {
+            return;
+        }
+        output.stopped = true;
+        let mut bytes = output.encoder.release();
+        if output.clock_running {
+            bytes.push(STOP);
+        }
+        let _ = output.write(&bytes);
+    }
+}
+
+// sends clock pulses at the rate of the current tempo estimation
+fn clock(output: Arc<Mutex<Output>>, tempo: Arc<Mutex<Tempo>>) {
+    // the pulses are scheduled against absolute deadlines so they don't drift
+    let mut deadline = Instant::now();
+    loop {
+        let tick = {
+            let tempo = tempo.lock().unwrap();
+            match (tempo.tick, tempo.last_beat) {
+                (Some(tick), Some(last_beat))
+                    if last_beat.elapsed() < tick * CLOCKS_PER_BEAT * STOP_AFTER_BEATS =>
+                {
+                    Some(tick)
+                }
+                _ => None,
+            }
+        };
+        {
+            let mut output = output.lock().unwrap();
+            if output.stopped {
+                return;
+            }
+            let message = match tick {
+                Some(_) if output.clock_running => Some(CLOCK),
+                Some(_) => Some(START),
+                None if output.clock_running => Some(STOP),
+                None => None,
+            };
+            output.clock_running = tick.is_some();
+            if let Some(message) = message {
+                if output.write(&[message]).is_err() {
+                    return;
+                }
+            }
+        }
+        let interval = tick.unwrap_or(Duration::from_millis(100));
+        deadline += interval;
+        let now = Instant::now();
+        if deadline > now {
+            sleep(deadline - now);
+        } else if now - deadline > interval {
+            // start over after a stall instead of sending a burst of pulses
+            deadline = now;
+        }
+    }
+}
+
+fn send_frames(
+    path: String,
+    output: Arc<Mutex<Output>>,
+    clock_tempo: Arc<Mutex<Tempo>>,
+    mut rx: broadcast::Receiver<SpectrumFrame>,
+) {
+    let mut tempo = TempoEstimator::new();
+
+    while let Ok(frame) = rx.recv() {
+        if frame.beat && tempo.beat(frame.captured) {
+            let mut state = clock_tempo.lock().unwrap();
+            state.last_beat = Some(frame.captured);
+            state.tick = tempo.bpm().map(|bpm| {
+                Duration::from_secs(60) / (bpm.round() as u32 * CLOCKS_PER_BEAT)
+            });
+        }
+
+        let mut output = output.lock().unwrap();
+        if output.stopped {
+            return;
+        }
+        let bytes = output.encoder.encode(&frame.bins);
+        if bytes.is_empty() {
+            continue;
+        }
+        if let Err(e) = output.write(&bytes) {
+            println!("Failed to write to MIDI output {}: {}", path, e);
+            return;
+        }
+    }
+    // the analysis is over
+    Sink { output: output }.stop();
+}
+
+// open the MIDI output and send the frames of `rx` in the background
+pub fn midi(
+    path: String,
+    settings: Settings,
+    rx: broadcast::Receiver<SpectrumFrame>,
+) -> Option<Sink> {
+    let file = match OpenOptions::new().write(true).open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Failed to open MIDI output {}: {}", path, e);
+            return None;
+        }
+    };
+    if file.metadata().map(|m| m.is_file()).unwrap_or(false) {
+        println!(
+            "MIDI output {} is a regular file, only raw MIDI devices are supported",
+            path
+        );
+        return None;
+    }
+    let output = Arc::new(Mutex::new(Output {
+        file: Box::new(file),
+        encoder: Encoder::new(settings),
+        clock_running: false,
+        stopped: false,
+    }));
+
+    let clock_tempo = Arc::new(Mutex::new(Tempo {
+        tick: None,
+        last_beat: None,
+    }));
+    {
+        let output = Arc::clone(&output);
+        let clock_tempo = Arc::clone(&clock_tempo);
+        spawn(move || clock(output, clock_tempo));
+    }
+    {
+        let output = Arc::clone(&output);
+        spawn(move || send_frames(path, output, clock_tempo, rx));
+    }
+    Some(Sink { output: output })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Encoder, Settings};
+
+    fn encoder() -> Encoder {
+        Encoder::new(Settings {
+            channel: 0,
+            threshold: 0.5,
+            band_count: 2,
+        })
+    }
+
+    // the note-on and note-off messages of the encoded bytes
+    fn notes(bytes: &[u8]) -> Vec<Vec<u8>> {
+        bytes
+            .chunks(3)
+            .filter(|m| m[0] & 0xf0 == 0x80 || m[0] & 0xf0 == 0x90)
+            .map(|m| m.to_vec())
+            .collect()
+    }
+
+    fn controls(bytes: &[u8]) -> Vec<Vec<u8>> {
+        bytes
+            .chunks(3)
+            .filter(|m| m[0] & 0xf0 == 0xb0)
+            .map(|m| m.to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_notes_have_hysteresis() {
+        let mut encoder = encoder();
+        assert_eq!(notes(&encoder.encode(&[0.6, 0.0])), vec![vec![0x90, 33, 76]]);
+        // still above the release level
+        assert!(notes(&encoder.encode(&[0.45, 0.0])).is_empty());
+        assert_eq!(notes(&encoder.encode(&[0.3, 0.0])), vec![vec![0x80, 33, 0]]);
+        assert!(notes(&encoder.encode(&[0.3, 0.0])).is_empty());
+
+        // quiet notes still get a velocity
+        let mut encoder = Encoder::new(Settings {
+            channel: 0,
+            threshold: 0.001,
+            band_count: 2,
+        });
+        assert_eq!(notes(&encoder.encode(&[0.002, 0.0])), vec![vec![0x90, 33, 1]]);
+    }
+
+    #[test]
+    fn test_release_notes_that_are_on() {
+        let mut encoder = encoder();
+        encoder.encode(&[0.6, 0.0, 0.7]);
+        assert_eq!(
+            encoder.release(),
+            vec![0x80, 33, 0, 0x80, 35, 0, 0xb0, 123, 0]
+        );
+        assert_eq!(encoder.release(), vec![0xb0, 123, 0]);
+    }
+
+    #[test]
+    fn test_unchanged_controls_are_skipped() {
+        let mut encoder = encoder();
+        assert_eq!(
+            controls(&encoder.encode(&[0.2, 0.4])),
+            vec![vec![0xb0, 20, 25], vec![0xb0, 21, 50]]
+        );
+        assert!(controls(&encoder.encode(&[0.2, 0.4])).is_empty());
+        assert_eq!(
+            controls(&encoder.encode(&[0.2, 1.0])),
+            vec![vec![0xb0, 21, 127]]
+        );
+    }
+}
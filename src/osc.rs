@@ -0,0 +1,191 @@
+// Open Sound Control output for tools like Resolume, TouchDesigner or QLab.
+//
+// Every frame is sent as one bundle containing
+//   <prefix>/band/N  f  energy of the N-th band
+//   <prefix>/rms     f
+//   <prefix>/peak    f
+//   <prefix>/beat    i  only present when a beat was detected since the previous bundle, the
+//                       beats of frames skipped because of `--osc-fps` are kept
+//   <prefix>/pitch   f f i  fundamental in Hz, confidence and MIDI note, only present when
+//                           a pitch was detected
+//   <prefix>/chroma  f*12   energy per pitch class starting at C
+//   <prefix>/key     i i f  tonic pitch class, 1 for minor, confidence
+//   <prefix>/chord   i i f  root pitch class, 1 for minor, confidence
+//   <prefix>/feature/<name>   f  spectral descriptors, e.g. centroid or flatness
+//   <prefix>/loudness/<name>  f  momentary, short_term and integrated LUFS, range in LU
+//                                and true_peak in dBTP
+
+use std::net::UdpSocket;
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use bands;
+use broadcast;
+use chroma::Mode;
+use frame::SpectrumFrame;
+
+enum Argument {
+    Float(f32),
+    Int(i32),
+}
+
+// strings are null terminated and padded to a multiple of four bytes
+fn write_string(wrt: &mut Vec<u8>, s: &str) {
+    wrt.extend(s.as_bytes());
+    wrt.push(0);
+    while wrt.len() % 4 != 0 {
+        wrt.push(0);
+    }
+}
+
+fn encode_message(address: &str, args: &[Argument]) -> Vec<u8> {
+    let mut wrt = vec![];
+    write_string(&mut wrt, address);
+
+    let tags: String = ::std::iter::once(',')
+        .chain(args.iter().map(|a| match *a {
+            Argument::Float(_) => 'f',
+            Argument::Int(_) => 'i',
+        }))
+        .collect();
+    write_string(&mut wrt, &tags);
+
+    for arg in args {
+        match *arg {
+            Argument::Float(v) => wrt.write_f32::<BigEndian>(v).unwrap(),
+            Argument::Int(v) => wrt.write_i32::<BigEndian>(v).unwrap(),
+        }
+    }
+    wrt
+}
+
+fn encode_bundle(messages: &[Vec<u8>]) -> Vec<u8> {
+    let mut wrt = vec![];
+    write_string(&mut wrt, "#bundle");
+    // the special time tag 1 means "immediately"
+    wrt.write_u64::<BigEndian>(1).unwrap();
+    for message in messages {
+        wrt.write_i32::<BigEndian>(message.len() as i32).unwrap();
+        wrt.extend(message);
+    }
+    wrt
+}
+
+fn mode_argument(mode: Mode) -> Argument {
+    Argument::Int(if mode == Mode::Minor { 1 } else { 0 })
+}
+
+fn encode_frame(prefix: &str, band_count: usize, frame: &SpectrumFrame) -> Vec<u8> {
+    let mut messages: Vec<Vec<u8>> = bands::group(&frame.bins, band_count)
+        .into_iter()
+        .enumerate()
+        .map(|(i, v)| {
+            encode_message(&format!("{}/band/{}", prefix, i), &[Argument::Float(v)])
+        })
+        .collect();
+    messages.push(encode_message(&format!("{}/rms", prefix), &[Argument::Float(frame.rms)]));
+    messages.push(encode_message(&format!("{}/peak", prefix), &[Argument::Float(frame.peak)]));
+    if frame.beat {
+        messages.push(encode_message(&format!("{}/beat", prefix), &[Argument::Int(1)]));
+    }
+    if let Some(ref pitch) = frame.pitch {
+        messages.push(encode_message(
+            &format!("{}/pitch", prefix),
+            &[
+                Argument::Float(pitch.frequency),
+                Argument::Float(pitch.confidence),
+                Argument::Int(pitch.note as i32),
+            ],
+        ));
+    }
+    messages.push(encode_message(
+        &format!("{}/chroma", prefix),
+        &frame
+            .chroma
+            .iter()
+            .map(|v| Argument::Float(*v))
+            .collect::<Vec<_>>(),
+    ));
+    if let Some(ref key) = frame.key {
+        messages.push(encode_message(
+            &format!("{}/key", prefix),
+            &[
+                Argument::Int(key.tonic as i32),
+                mode_argument(key.mode),
+                Argument::Float(key.confidence),
+            ],
+        ));
+    }
+    if let Some(ref chord) = frame.chord {
+        messages.push(encode_message(
+            &format!("{}/chord", prefix),
+            &[
+                Argument::Int(chord.root as i32),
+                mode_argument(chord.mode),
+                Argument::Float(chord.confidence),
+            ],
+        ));
+    }
+    for (name, value) in frame.features.named() {
+        messages.push(encode_message(
+            &format!("{}/feature/{}", prefix, name),
+            &[Argument::Float(value)],
+        ));
+    }
+    for (name, value) in frame.loudness.named() {
+        messages.push(encode_message(
+            &format!("{}/loudness/{}", prefix, name),
+            &[Argument::Float(value)],
+        ));
+    }
+    encode_bundle(&messages)
+}
+
+pub fn osc(
+    target: String,
+    prefix: String,
+    band_count: usize,
+    mut rx: broadcast::Receiver<SpectrumFrame>,
+) {
+    let sock = UdpSocket::bind("[::]:0").unwrap();
+
+    while let Ok(frame) = rx.recv() {
+        let bytes = encode_frame(&prefix, band_count, &frame);
+        if let Err(e) = sock.send_to(&bytes, &target) {
+            println!("Failed to send OSC bundle to {}: {}", target, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_bundle, encode_message, Argument};
+
+    #[test]
+    fn test_message_encoding() {
+        let message = encode_message("/a/rms", &[Argument::Float(0.5)]);
+        let mut expected = b"/a/rms\0\0,f\0\0".to_vec();
+        expected.extend(&[0x3f, 0x00, 0x00, 0x00]);
+        assert_eq!(message, expected);
+
+        // strings that fill a multiple of four bytes still get a terminating null
+        let message = encode_message(
+            "/key",
+            &[Argument::Int(9), Argument::Float(1.0), Argument::Float(0.0)],
+        );
+        let mut expected = b"/key\0\0\0\0,iff\0\0\0\0".to_vec();
+        expected.extend(&[0, 0, 0, 9, 0x3f, 0x80, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(message, expected);
+    }
+
+    #[test]
+    fn test_bundle_encoding() {
+        let bundle = encode_bundle(&[vec![1, 2, 3, 4], vec![5, 6, 7, 8, 9, 10, 11, 12]]);
+        let mut expected = b"#bundle\0".to_vec();
+        // time tag "immediately"
+        expected.extend(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        expected.extend(&[0, 0, 0, 4, 1, 2, 3, 4]);
+        expected.extend(&[0, 0, 0, 8, 5, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(bundle, expected);
+    }
+}
@@ -0,0 +1,198 @@
+// Text drawn on top of the presets to calibrate installations.
+//
+// The bottom edge is labeled with the note and frequency of every A where the preset puts
+// those bins, for the waterfall from left to right and for the bars from the center out.
+// Presets without a frequency axis get no labels. The top left corner shows the
+// strongest bin, the frame rate, the latency of the analysis and the gains, a marker in the
+// top right corner lights up on every beat. The default preset shows its loudness meter at
+// the right edge only while the overlay is visible.
+
+use glium::Surface;
+use glium::backend::Facade;
+
+use frame::SpectrumFrame;
+use pitch::Pitch;
+use simple_decoder::PER_OCTAVE;
+use text::{self, TextRenderer};
+
+// font pixels are drawn as blocks of this many pixels
+const SCALE: u32 = 2;
+// seconds the beat marker stays lit
+const BEAT_DURATION: f32 = 0.1;
+// weight of the previous frame rate estimate
+const FPS_SMOOTHING: f32 = 0.95;
+
+const LABEL_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const BEAT_COLOR: [f32; 4] = [1.0, 0.2, 0.2, 1.0];
+
+// e.g. "55Hz" or "1.8kHz"
+pub fn format_hz(frequency: f32) -> String {
+    if frequency >= 1000.0 {
+        format!("{:.1}kHz", frequency / 1000.0)
+    } else {
+        format!("{:.0}Hz", frequency)
+    }
+}
+
+// note name of a frequency, e.g. "A4"
+pub fn note_name(frequency: f32) -> String {
+    Pitch::from_frequency(frequency, 1.0).name()
+}
+
+// index and label of every bin that starts an octave, the bins start at an A
+pub fn axis_labels(freqs: &[f32]) -> Vec<(usize, String)> {
+    freqs
+        .iter()
+        .enumerate()
+        .filter(|&(v, _)| v % PER_OCTAVE == 0)
+        .map(|(v, &f)| (v, format!("{} {}", note_name(f), format_hz(f))))
+        .collect()
+}
+
+// how a preset lays out the bins along the bottom edge
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Axis {
+    // from the left to the right edge
+    Linear,
+    // from the center out to both edges
+    Mirrored,
+}
+
+// horizontal pixel of bin `v` of `count` bins, mirrored bins are labeled on the right half
+pub fn axis_position(axis: Axis, v: usize, count: usize, width: u32) -> u32 {
+    let t = v as f32 / count as f32;
+    let x = match axis {
+        Axis::Linear => t,
+        Axis::Mirrored => 0.5 + t / 2.0,
+    };
+    (x * width as f32) as u32
+}
+
+pub struct Overlay {
+    text: TextRenderer,
+    fps: f32,
+    pub visible: bool,
+}
+
+impl Overlay {
+    pub fn new<F: Facade>(facade: &F, visible: bool) -> Overlay {
+        Overlay {
+            text: TextRenderer::new(facade),
+            fps: 0.0,
+            visible: visible,
+        }
+    }
+
+    // account for a frame drawn `dt` seconds after the previous one
+    pub fn tick(&mut self, dt: f32) {
+        if dt <= 0.0 {
+            return;
+        }
+        self.fps = if self.fps == 0.0 {
+            1.0 / dt
+        } else {
+            FPS_SMOOTHING * self.fps + (1.0 - FPS_SMOOTHING) / dt
+        };
+    }
+
+    pub fn draw<F: Facade, S: Surface>(
+        &self,
+        facade: &F,
+        target: &mut S,
+        spec: &SpectrumFrame,
+        axis: Option<Axis>,
+        gain: f32,
+        beat_age: f32,
+        pixel_ratio: f32,
+    ) {
+        if !self.visible {
+            return;
+        }
+        let (width, height) = target.get_dimensions();
+        let scale = u32::max((SCALE as f32 * pixel_ratio).round() as u32, 1);
+
+        let count = spec.freqs.len();
+        let labels = match axis {
+            Some(axis) => axis_labels(&spec.freqs)
+                .into_iter()
+                .map(|(v, label)| (axis_position(axis, v, count, width), label))
+                .collect(),
+            None => vec![],
+        };
+        for (x, label) in labels {
+            let lines = vec![label];
+            let (_, label_height) = text::size(&lines, scale);
+            self.text.draw(
+                facade,
+                target,
+                &lines,
+                (x, height.saturating_sub(label_height)),
+                scale,
+                LABEL_COLOR,
+            );
+        }
+
+        let peak = spec.bins
+            .iter()
+            .enumerate()
+            .fold(None, |best: Option<(usize, f32)>, (v, &value)| match best {
+                Some((_, max)) if max >= value.abs() => best,
+                _ => Some((v, value.abs())),
+            });
+        let peak = match peak {
+            Some((v, value)) if value > 0.0 => {
+                format!("peak {} {}", note_name(spec.freqs[v]), format_hz(spec.freqs[v]))
+            }
+            _ => "peak -".to_string(),
+        };
+        let latency = spec.captured.elapsed();
+        let lines = vec![
+            peak,
+            format!(
+                "{:.1} fps  latency {}ms",
+                self.fps,
+                latency.as_secs() * 1000 + latency.subsec_nanos() as u64 / 1_000_000
+            ),
+            format!("agc x{:.2}  gain x{:.2}", spec.gain, gain),
+        ];
+        self.text
+            .draw(facade, target, &lines, (0, 0), scale, LABEL_COLOR);
+
+        if beat_age < BEAT_DURATION {
+            let lines = vec!["BEAT".to_string()];
+            let (beat_width, _) = text::size(&lines, scale);
+            self.text.draw(
+                facade,
+                target,
+                &lines,
+                (width.saturating_sub(beat_width), 0),
+                scale,
+                BEAT_COLOR,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{axis_labels, axis_position, Axis};
+    use simple_decoder::semitone_freq;
+
+    #[test]
+    fn test_axis_labels_every_octave() {
+        let freqs: Vec<f32> = (0..84).map(|v| semitone_freq(v) as f32).collect();
+        let labels = axis_labels(&freqs);
+        assert_eq!(labels.len(), 7);
+        assert_eq!(labels[0], (0, "A1 55Hz".to_string()));
+        assert_eq!(labels[1], (12, "A2 110Hz".to_string()));
+        assert_eq!(labels[6], (72, "A7 3.5kHz".to_string()));
+    }
+
+    #[test]
+    fn test_axis_positions() {
+        assert_eq!(axis_position(Axis::Linear, 0, 84, 840), 0);
+        assert_eq!(axis_position(Axis::Linear, 42, 84, 840), 420);
+        assert_eq!(axis_position(Axis::Mirrored, 0, 84, 840), 420);
+        assert_eq!(axis_position(Axis::Mirrored, 42, 84, 840), 630);
+    }
+}
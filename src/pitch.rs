@@ -0,0 +1,194 @@
+// Monophonic pitch tracking using the harmonic product spectrum of the SimpleDecoder fft.
+//
+// The magnitude spectrum is downsampled by the factors 1..HARMONICS and multiplied, since
+// all harmonics of a tone line up at its fundamental the product peaks there. The result is
+// checked for octave errors afterwards.
+
+use simple_decoder::{SimpleDecoder, KAMMER_TON, PER_OCTAVE};
+use window;
+
+const HARMONICS: usize = 5;
+const MIN_FREQ: f32 = 50.0;
+const MAX_FREQ: f32 = 1500.0;
+// relative magnitude missing harmonics are clamped to
+const HARMONIC_FLOOR: f32 = 0.01;
+// relative magnitude the fundamental needs to reach to be accepted
+const FUNDAMENTAL_LEVEL: f32 = 0.2;
+// below this level the input is considered silence
+const SILENCE: f32 = 0.001;
+
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pitch {
+    // fundamental frequency in Hz
+    pub frequency: f32,
+    // nearest MIDI note number
+    pub note: u8,
+    // deviation from the nearest note, -50 to 50
+    pub cents: f32,
+    // share of the spectral energy explained by the harmonics of the fundamental, 0 to 1
+    pub confidence: f32,
+}
+
+impl Pitch {
+    pub fn from_frequency(frequency: f32, confidence: f32) -> Pitch {
+        // the kammerton is A4 which is MIDI note 69
+        let note = 69.0 + PER_OCTAVE as f32 * (frequency / KAMMER_TON as f32).log2();
+        let nearest = note.round();
+        Pitch {
+            frequency: frequency,
+            note: nearest as u8,
+            cents: (note - nearest) * 100.0,
+            confidence: confidence,
+        }
+    }
+
+    // e.g. "A4" or "C#3"
+    pub fn name(&self) -> String {
+        let octave = self.note as i32 / PER_OCTAVE as i32 - 1;
+        format!("{}{}", NOTE_NAMES[self.note as usize % PER_OCTAVE], octave)
+    }
+
+    // position within the octave, 0 is C and 12 would be the next C
+    pub fn pitch_class(&self) -> f32 {
+        (self.note as f32 + self.cents / 100.0) % PER_OCTAVE as f32
+    }
+}
+
+pub struct PitchDetector {
+    decoder: SimpleDecoder,
+    samples: Vec<f32>,
+}
+
+impl PitchDetector {
+    pub fn new(sample_count: usize, sample_rate: usize) -> Self {
+        PitchDetector {
+            decoder: SimpleDecoder::new(sample_count, sample_rate),
+            samples: vec![0.0; sample_count],
+        }
+    }
+
+    fn bin_freq(&self, bin: f32) -> f32 {
+        bin * self.decoder.sample_rate as f32 / self.decoder.sample_count as f32
+    }
+
+    pub fn analyze(&mut self, samples: &[f32]) -> Option<Pitch> {
+        window::slide(&mut self.samples, samples);
+
+        let rms = (self.samples.iter().map(|v| v * v).sum::<f32>() / self.samples.len() as f32)
+            .sqrt();
+        if rms < SILENCE {
+            return None;
+        }
+
+        let magnitudes = self.decoder.magnitudes(&self.samples);
+        let resolution = self.bin_freq(1.0);
+        let min_bin = usize::max((MIN_FREQ / resolution) as usize, 1);
+        let max_bin = usize::min(
+            (MAX_FREQ / resolution) as usize,
+            (magnitudes.len() - 1) / HARMONICS,
+        );
+        if min_bin >= max_bin {
+            return None;
+        }
+
+        // sum of logarithms instead of the product to stay clear of underflows, missing
+        // harmonics are clamped to a floor so a single one doesn't veto a candidate
+        let peak = magnitudes.iter().cloned().fold(0.0, f32::max);
+        let floor = peak * HARMONIC_FLOOR + 1e-12;
+        let (mut fundamental, _) = (min_bin..max_bin)
+            .map(|bin| {
+                let hps: f32 = (1..HARMONICS + 1)
+                    .map(|h| magnitudes[bin * h].max(floor).ln())
+                    .sum();
+                (bin, hps)
+            })
+            .fold((0, ::std::f32::MIN), |best, current| if current.1 > best.1 {
+                current
+            } else {
+                best
+            });
+
+        // tones with few harmonics tend to end up an octave (or more) too low, in that case
+        // move up to the first multiple that actually carries energy
+        let strong = |bin: usize| {
+            magnitudes[bin - 1..bin + 2].iter().cloned().fold(0.0, f32::max) >=
+                peak * FUNDAMENTAL_LEVEL
+        };
+        if !strong(fundamental) {
+            let base = fundamental;
+            for k in 2..17 {
+                let center = base * k;
+                let width = k / 2 + 1;
+                if center + width + 1 >= magnitudes.len() {
+                    break;
+                }
+                let best = (center - width..center + width + 1).fold(center, |a, b| {
+                    if magnitudes[b] > magnitudes[a] { b } else { a }
+                });
+                if strong(best) {
+                    fundamental = best;
+                    break;
+                }
+            }
+        }
+
+        // refine the peak position by fitting a parabola through the neighbouring bins
+        let (a, b, c) = (
+            magnitudes[fundamental - 1],
+            magnitudes[fundamental],
+            magnitudes[fundamental + 1],
+        );
+        let denominator = a - 2.0 * b + c;
+        let offset = if denominator.abs() > 1e-12 {
+            (0.5 * (a - c) / denominator).max(-0.5).min(0.5)
+        } else {
+            0.0
+        };
+
+        let total: f32 = magnitudes.iter().map(|v| v * v).sum();
+        let harmonic: f32 = (1..HARMONICS + 1)
+            .map(|h| fundamental * h)
+            .take_while(|&bin| bin + 1 < magnitudes.len())
+            .map(|bin| magnitudes[bin - 1..bin + 2].iter().map(|v| v * v).sum::<f32>())
+            .sum();
+        let confidence = if total > 0.0 {
+            (harmonic / total).min(1.0)
+        } else {
+            0.0
+        };
+
+        Some(Pitch::from_frequency(
+            self.bin_freq(fundamental as f32 + offset),
+            confidence,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PitchDetector;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn test_detects_sine_with_harmonics() {
+        let sample_rate = 44100;
+        let frequency = 220.0;
+        let samples: Vec<f32> = (0..8192)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                (1..4)
+                    .map(|h| (2.0 * PI * frequency * h as f32 * t).sin() / h as f32)
+                    .sum()
+            })
+            .collect();
+
+        let mut detector = PitchDetector::new(8192, sample_rate);
+        let pitch = detector.analyze(&samples).expect("a pitch to be detected");
+        assert_eq!(pitch.name(), "A3");
+        assert!((pitch.frequency - frequency).abs() < 3.0);
+    }
+}
@@ -1,7 +1,8 @@
 use super::simple_decoder;
-use std::time::{Duration, Instant};
 
 
+// the ffts are skipped until this much audio arrived, counted in samples rather than wall
+// clock time so rendering and replaying faster than real time yields the same spectra
 const SAMPLING_DURATION: u64 = 16; // in milliseconds
 
 pub struct Processor {
@@ -9,7 +10,6 @@ pub struct Processor {
     samples: Vec<f32>,
     fresh_samples: usize,
     needed_samples: usize,
-    draw_time: Option<Instant>,
 }
 
 impl Processor {
@@ -20,42 +20,23 @@ impl Processor {
         Processor {
             decoder: dec,
             samples: samples,
-            fresh_samples: 0,
+            // the first chunk is always analyzed
+            fresh_samples: needed_samples,
             needed_samples: needed_samples,
-            draw_time: None,
         }
     }
 
-    fn get_elapsed_time(&mut self) -> Duration {
-        let elapsed = if let Some(dt) = self.draw_time {
-            dt.elapsed()
-        } else {
-            let n = Instant::now();
-            let e = n.elapsed();
-            self.draw_time = Some(n);
-            e
-        };
-        elapsed
-    }
-
     pub fn process(&mut self, samples: Vec<f32>) -> Option<Vec<f32>> {
-        let elapsed = self.get_elapsed_time();
         let new = usize::min(samples.len(), self.decoder.sample_count);
         self.fresh_samples += samples.len();
         self.samples.rotate_right(new);
         self.samples.splice(..new, samples.into_iter().take(new));
 
-        // if we are being called too often start skipping frames
-        if elapsed < Duration::from_millis(SAMPLING_DURATION) {
-            return None;
-        }
-
         // if there are enough new samples do all the expensive stuff
         if self.fresh_samples >= self.needed_samples {
             let s = &self.samples[..self.decoder.sample_count];
             let out = self.decoder.decode(s);
             self.fresh_samples = 0;
-            self.draw_time = Some(Instant::now());
             Some(out)
         } else {
             None
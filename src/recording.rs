@@ -0,0 +1,357 @@
+// Recording of the raw samples and their replay, to reproduce problems of the analysis and to
+// run the visualizer without audio hardware. The analyzed frames can be recorded as well and
+// replayed straight into the sinks, e.g. to preview a show on the LEDs without the music.
+//
+// A recording starts with a header: the magic bytes "SVRAW", the format version, the number
+// of channels and the sample rate. It is followed by one record per chunk of the pipeline:
+//   u64  nanoseconds since the first chunk was captured
+//   u8   1 if the chunk has a presentation timestamp
+//   u64  presentation timestamp in nanoseconds, written as 0 without one
+//   u32  number of samples
+//   f32  the samples, interleaved for stereo like they left the appsink
+// All numbers are little endian.
+//
+// Frames are recorded as JSON, one line per frame with the nanoseconds since the first frame
+// as `offset` and the serialized `frame`. The raw samples of the frames are left out so the
+// oscilloscope and the goniometer stay empty when they are replayed. JSON has no NaN or
+// infinity, frames with such values are left out as they could not be read back.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::sync::mpsc::Sender;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure::Error;
+use serde_json;
+
+use broadcast;
+use frame::SpectrumFrame;
+use gst::{self, Samples};
+
+const MAGIC: &[u8; 5] = b"SVRAW";
+const VERSION: u8 = 1;
+const SAMPLE_RATE: u32 = 44100;
+// upper bound of the samples of a record, the chunks of the pipeline are far smaller so
+// anything above means the recording is corrupt
+const MAX_SAMPLES: usize = 1 << 20;
+
+#[derive(Debug, Fail)]
+pub enum RecordingError {
+    #[fail(display = "{} is not a recording of raw samples", _0)]
+    NotARecording(String),
+    #[fail(display = "unsupported version {} of recording {}", _1, _0)]
+    UnsupportedVersion(String, u8),
+    #[fail(display = "invalid frame in line {} of {}: {}", _1, _0, _2)]
+    InvalidFrame(String, usize, String),
+}
+
+#[derive(Serialize)]
+struct FrameRecordRef<'a> {
+    offset: u64,
+    frame: &'a SpectrumFrame,
+}
+
+#[derive(Deserialize)]
+struct FrameRecord {
+    offset: u64,
+    frame: SpectrumFrame,
+}
+
+// every number of the frame can be written as JSON
+fn is_finite(frame: &SpectrumFrame) -> bool {
+    let mut values: Vec<f32> = vec![frame.gain, frame.rms, frame.peak];
+    values.extend(frame.bins.iter().chain(&frame.freqs).chain(&frame.chroma));
+    values.extend(frame.features.named().into_iter().map(|(_, v)| v));
+    values.extend(frame.loudness.named().into_iter().map(|(_, v)| v));
+    if let Some(ref pitch) = frame.pitch {
+        values.extend(&[pitch.frequency, pitch.cents, pitch.confidence]);
+    }
+    values.extend(frame.key.iter().map(|key| key.confidence));
+    values.extend(frame.chord.iter().map(|chord| chord.confidence));
+    values.iter().all(|v| v.is_finite())
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+// time passed since `start`, zero for instants before it
+fn offset(start: Instant, instant: Instant) -> Duration {
+    if instant > start {
+        instant - start
+    } else {
+        Duration::new(0, 0)
+    }
+}
+
+// sleep until `offset` nanoseconds of the recording have passed since `start` when replaying
+// `speed` times faster
+fn wait_for(start: Instant, offset: u64, speed: f32) {
+    let due = start + Duration::from_nanos((offset as f64 / speed as f64) as u64);
+    let now = Instant::now();
+    if due > now {
+        sleep(due - now);
+    }
+}
+
+fn write_header<W: Write>(writer: &mut W, channels: usize) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_u8(VERSION)?;
+    writer.write_u8(channels as u8)?;
+    writer.write_u32::<LittleEndian>(SAMPLE_RATE)
+}
+
+fn write_record<W: Write>(writer: &mut W, offset: Duration, samples: &Samples) -> io::Result<()> {
+    writer.write_u64::<LittleEndian>(nanos(offset))?;
+    writer.write_u8(samples.pts.is_some() as u8)?;
+    writer.write_u64::<LittleEndian>(samples.pts.unwrap_or(0))?;
+    match samples.stereo {
+        Some(ref stereo) => {
+            writer.write_u32::<LittleEndian>(2 * stereo.left.len() as u32)?;
+            for (l, r) in stereo.left.iter().zip(stereo.right.iter()) {
+                writer.write_f32::<LittleEndian>(*l)?;
+                writer.write_f32::<LittleEndian>(*r)?;
+            }
+        }
+        None => {
+            writer.write_u32::<LittleEndian>(samples.data.len() as u32)?;
+            for v in samples.data.iter() {
+                writer.write_f32::<LittleEndian>(*v)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_samples<R: Read>(reader: &mut R, channels: usize) -> io::Result<(Duration, Samples)> {
+    let offset = reader.read_u64::<LittleEndian>()?;
+    let has_pts = reader.read_u8()? == 1;
+    let pts = reader.read_u64::<LittleEndian>()?;
+    let count = reader.read_u32::<LittleEndian>()? as usize;
+    if count > MAX_SAMPLES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("record of {} samples", count),
+        ));
+    }
+    let mut interleaved = Vec::with_capacity(count);
+    for _ in 0..count {
+        interleaved.push(reader.read_f32::<LittleEndian>()?);
+    }
+
+    let (data, stereo) = gst::deinterleave(&interleaved, channels);
+    Ok((
+        Duration::new(offset / 1_000_000_000, (offset % 1_000_000_000) as u32),
+        Samples {
+            pts: if has_pts { Some(pts) } else { None },
+            captured: Instant::now(),
+            data: data,
+            stereo: stereo,
+        },
+    ))
+}
+
+// the offset of the next record and its samples, `None` at the end of the recording, which
+// may be cut off in the middle of a record when the recording was interrupted
+fn read_record<R: Read>(reader: &mut R, channels: usize) -> io::Result<Option<(Duration, Samples)>> {
+    match read_samples(reader, channels) {
+        Ok(record) => Ok(Some(record)),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub struct Recorder {
+    writer: BufWriter<File>,
+    // capture instant of the first chunk, the offsets are relative to it
+    start: Option<Instant>,
+}
+
+impl Recorder {
+    pub fn create(path: &str, channels: usize) -> Result<Recorder, Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_header(&mut writer, channels)?;
+        Ok(Recorder {
+            writer: writer,
+            start: None,
+        })
+    }
+
+    pub fn write(&mut self, samples: &Samples) -> Result<(), Error> {
+        let start = *self.start.get_or_insert(samples.captured);
+        write_record(&mut self.writer, offset(start, samples.captured), samples)?;
+        // nothing but the last chunk is lost when the process is killed
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// send the samples of the recording at `path` to `tx` with their original timing, `speed`
+// times faster
+pub fn replay(path: &str, speed: f32, tx: Sender<Samples>) -> Result<(), Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; 5];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|_| RecordingError::NotARecording(path.to_string()))?;
+    if &magic != MAGIC {
+        return Err(RecordingError::NotARecording(path.to_string()).into());
+    }
+    let version = reader.read_u8()?;
+    if version != VERSION {
+        return Err(RecordingError::UnsupportedVersion(path.to_string(), version).into());
+    }
+    let channels = reader.read_u8()? as usize;
+    let _sample_rate = reader.read_u32::<LittleEndian>()?;
+
+    let start = Instant::now();
+    while let Some((offset, mut samples)) = read_record(&mut reader, channels)? {
+        wait_for(start, nanos(offset), speed);
+        samples.captured = Instant::now();
+        if tx.send(samples).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+pub struct FrameRecorder {
+    writer: BufWriter<File>,
+    start: Option<Instant>,
+}
+
+impl FrameRecorder {
+    pub fn create(path: &str) -> Result<FrameRecorder, Error> {
+        Ok(FrameRecorder {
+            writer: BufWriter::new(File::create(path)?),
+            start: None,
+        })
+    }
+
+    pub fn write(&mut self, frame: &SpectrumFrame) -> Result<(), Error> {
+        if !is_finite(frame) {
+            println!(
+                "Left frame {} out of the recording, it has values that are not finite",
+                frame.sequence
+            );
+            return Ok(());
+        }
+        let start = *self.start.get_or_insert(frame.captured);
+        let record = FrameRecordRef {
+            offset: nanos(offset(start, frame.captured)),
+            frame: frame,
+        };
+        serde_json::to_writer(&mut self.writer, &record)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// hand the frames recorded at `path` to the sinks of `tx` with their original timing, `speed`
+// times faster
+pub fn replay_frames(
+    path: &str,
+    speed: f32,
+    tx: &broadcast::Sender<SpectrumFrame>,
+) -> Result<(), Error> {
+    let reader = BufReader::new(File::open(path)?);
+    let start = Instant::now();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: FrameRecord = serde_json::from_str(&line).map_err(|e| {
+            RecordingError::InvalidFrame(path.to_string(), number + 1, e.to_string())
+        })?;
+        wait_for(start, record.offset, speed);
+        let mut frame = record.frame;
+        frame.captured = Instant::now();
+        tx.send_at(frame.captured, frame);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_finite, read_record, write_record, FrameRecord, FrameRecordRef};
+    use features::Features;
+    use frame::SpectrumFrame;
+    use gst::{Samples, Stereo};
+    use loudness::Loudness;
+    use serde_json;
+    use std::f32;
+    use std::io::Cursor;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_record_round_trip() {
+        let samples = Samples {
+            pts: Some(42),
+            captured: Instant::now(),
+            data: vec![0.5, 0.0],
+            stereo: Some(Stereo {
+                left: vec![1.0, 0.5],
+                right: vec![0.0, -0.5],
+            }),
+        };
+        let mut buffer = vec![];
+        write_record(&mut buffer, Duration::from_millis(20), &samples).unwrap();
+
+        let mut reader = Cursor::new(buffer);
+        let (offset, read) = read_record(&mut reader, 2).unwrap().unwrap();
+        assert_eq!(offset, Duration::from_millis(20));
+        assert_eq!(read.pts, Some(42));
+        assert_eq!(read.data, vec![0.5, 0.0]);
+        let stereo = read.stereo.unwrap();
+        assert_eq!(stereo.left, vec![1.0, 0.5]);
+        assert_eq!(stereo.right, vec![0.0, -0.5]);
+        assert!(read_record(&mut reader, 2).unwrap().is_none());
+    }
+
+    fn silent_frame() -> SpectrumFrame {
+        SpectrumFrame {
+            sequence: 0,
+            pts: None,
+            captured: Instant::now(),
+            sample_rate: 44100,
+            fft_sizes: vec![8192],
+            freqs: vec![55.0, 58.3],
+            bins: vec![0.0, 0.0],
+            gain: 1.0,
+            rms: 0.0,
+            peak: 0.0,
+            beat: false,
+            pitch: None,
+            chroma: vec![0.0; 12],
+            key: None,
+            chord: None,
+            features: Features::default(),
+            loudness: Loudness::default(),
+            samples: vec![],
+            stereo: None,
+        }
+    }
+
+    #[test]
+    fn test_silent_frame_round_trip() {
+        let frame = silent_frame();
+        assert!(is_finite(&frame));
+        let line = serde_json::to_string(&FrameRecordRef {
+            offset: 20,
+            frame: &frame,
+        }).unwrap();
+
+        let record: FrameRecord = serde_json::from_str(&line).unwrap();
+        assert_eq!(record.offset, 20);
+        assert_eq!(record.frame.bins, vec![0.0, 0.0]);
+        assert_eq!(record.frame.chroma, vec![0.0; 12]);
+
+        let mut invalid = silent_frame();
+        invalid.bins[1] = f32::NAN;
+        assert!(!is_finite(&invalid));
+    }
+}
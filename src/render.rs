@@ -0,0 +1,284 @@
+// Renders the visualizer for an audio file without a display.
+//
+// The file is decoded as fast as possible and cut into exactly `sample_rate / fps` samples per
+// video frame, every chunk is analyzed like live input and drawn by the same presets into an
+// offscreen framebuffer. Headless contexts use OSMesa on Linux so software Mesa is enough.
+// The frames are pushed into a GStreamer pipeline: one that writes PNG files, a custom one or
+// an encodebin muxing them with the original audio into MP4 or WebM. The video frames and the
+// audio carry timestamps from the start of the file so the muxer keeps them in sync.
+
+use std::time::Instant;
+
+use failure::Error;
+use glium::backend::Facade;
+use glium::glutin;
+use glium::texture::{MipmapsOption, RawImage2d, Texture2d, UncompressedFloatFormat};
+use glium;
+use gstreamer::{BinExt, Cast, ElementExt};
+use gstreamer;
+use gstreamer_app;
+
+use analysis::{self, Analyzer};
+use cues::{Change, Cue, CuePlayer, Show, Timecode};
+use gst::{self, FileDecoder, Samples, Stereo};
+use visual::{self, Renderer};
+
+const SAMPLE_RATE: u64 = 44100;
+
+#[derive(Debug, Fail)]
+pub enum RenderError {
+    #[fail(display = "failed to create an offscreen context: {}", _0)]
+    Context(String),
+    #[fail(display = "failed to decode {}: {}", _0, _1)]
+    Decode(String, String),
+    #[fail(display = "the encoding pipeline failed: {}", _0)]
+    Encode(String),
+    #[fail(display = "unknown video container of {}, use .mp4 or .webm", _0)]
+    UnknownContainer(String),
+}
+
+pub enum Output {
+    // PNG files named after a pattern, e.g. "frame%05d.png"
+    Frames(String),
+    // elements the raw RGBA frames are passed to
+    Pipeline(String),
+    // a video file with the soundtrack of the input, the container is picked by the extension
+    Video(String),
+}
+
+pub struct Options {
+    // audio file to render
+    pub input: String,
+    pub output: Output,
+    pub fps: u32,
+    pub width: u32,
+    pub height: u32,
+    pub channels: usize,
+    // applied at their position in the file
+    pub cues: Vec<Cue>,
+}
+
+// encoding profile of encodebin for the container of `path`
+fn profile(path: &str) -> Result<&'static str, RenderError> {
+    let extension = path.rsplit('.').next().unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "mp4" => Ok("video/quicktime,variant=iso:video/x-h264:audio/mpeg,mpegversion=4"),
+        "webm" => Ok("video/webm:video/x-vp8:audio/x-vorbis"),
+        _ => Err(RenderError::UnknownContainer(path.to_string())),
+    }
+}
+
+fn encoder(options: &Options) -> Result<(gstreamer::Pipeline, gstreamer_app::AppSrc), Error> {
+    let source = "appsrc name=src format=time block=true";
+    let launch = match options.output {
+        Output::Frames(ref pattern) => format!(
+            "{} ! videoconvert ! pngenc ! multifilesink location={}",
+            source,
+            gst::quote(pattern)
+        ),
+        Output::Pipeline(ref pipeline) => format!("{} ! {}", source, pipeline),
+        // the audio is decoded once more, untouched by the analysis
+        Output::Video(ref path) => format!(
+            "encodebin name=enc profile={} ! filesink location={}
+             {} ! videoconvert ! queue ! enc.
+             filesrc location={} ! decodebin ! audioconvert ! audioresample ! queue ! enc.
+             ",
+            gst::quote(profile(path)?),
+            gst::quote(path),
+            source,
+            gst::quote(&options.input)
+        ),
+    };
+    let pipeline = gstreamer::parse_launch(&launch)?
+        .dynamic_cast::<gstreamer::Pipeline>()
+        .expect("a pipeline to be created by the launch command");
+
+    let appsrc = pipeline
+        .get_by_name("src")
+        .expect("The source must exist")
+        .dynamic_cast::<gstreamer_app::AppSrc>()
+        .expect("An AppSrc instance");
+    appsrc.set_caps(&gstreamer::Caps::new_simple(
+        "video/x-raw",
+        &[
+            ("format", &"RGBA"),
+            ("width", &(options.width as i32)),
+            ("height", &(options.height as i32)),
+            ("framerate", &gstreamer::Fraction::new(options.fps as i32, 1)),
+        ],
+    ));
+
+    pipeline.set_state(gstreamer::State::Playing).into_result()?;
+    Ok((pipeline, appsrc))
+}
+
+// wait until everything pushed into the pipeline was written
+fn finish(pipeline: &gstreamer::Pipeline) -> Result<(), Error> {
+    let bus = pipeline.get_bus().expect("Pipeline should have a bus");
+    let result = match bus.timed_pop_filtered(
+        gstreamer::CLOCK_TIME_NONE,
+        &[gstreamer::MessageType::Eos, gstreamer::MessageType::Error],
+    ) {
+        Some(msg) => match msg.view() {
+            gstreamer::MessageView::Error(err) => {
+                Err(RenderError::Encode(err.get_error().to_string()).into())
+            }
+            _ => Ok(()),
+        },
+        None => Ok(()),
+    };
+    pipeline.set_state(gstreamer::State::Null).into_result()?;
+    result
+}
+
+// the samples of the next video frame, less at the end of the file
+struct Chunker {
+    mono: Vec<f32>,
+    left: Vec<f32>,
+    right: Vec<f32>,
+    stereo: bool,
+}
+
+impl Chunker {
+    fn push(&mut self, samples: Samples) {
+        self.mono.extend(samples.data);
+        if let Some(stereo) = samples.stereo {
+            self.left.extend(stereo.left);
+            self.right.extend(stereo.right);
+        }
+    }
+
+    fn take(&mut self, count: usize) -> (Vec<f32>, Option<Stereo>) {
+        let count = usize::min(count, self.mono.len());
+        let mono = self.mono.drain(..count).collect();
+        let stereo = if self.stereo {
+            let count = usize::min(count, usize::min(self.left.len(), self.right.len()));
+            Some(Stereo {
+                left: self.left.drain(..count).collect(),
+                right: self.right.drain(..count).collect(),
+            })
+        } else {
+            None
+        };
+        (mono, stereo)
+    }
+}
+
+pub fn render(settings: visual::Settings, options: Options) -> Result<(), Error> {
+    let context = glutin::HeadlessRendererBuilder::new(options.width, options.height)
+        .build()
+        .map_err(|e| RenderError::Context(e.to_string()))?;
+    let facade = glium::HeadlessRenderer::new(context)
+        .map_err(|e| RenderError::Context(e.to_string()))?;
+    // the presets are written for GLSL 3.30, the least software Mesa offers for core contexts
+    if !facade
+        .get_context()
+        .is_glsl_version_supported(&glium::Version(glium::Api::Gl, 3, 3))
+    {
+        return Err(RenderError::Context(format!(
+            "GLSL 3.30 is not supported by OpenGL {}",
+            facade.get_context().get_opengl_version_string()
+        )).into());
+    }
+    let texture = Texture2d::empty_with_format(
+        &facade,
+        UncompressedFloatFormat::U8U8U8U8,
+        MipmapsOption::NoMipmap,
+        options.width,
+        options.height,
+    )?;
+    let mut framebuffer = glium::framebuffer::SimpleFrameBuffer::new(&facade, &texture)?;
+
+    let mut decoder = FileDecoder::open(&options.input, options.channels)?;
+    let (pipeline, appsrc) = encoder(&options)?;
+    let mut analyzer = Analyzer::new(
+        analysis::window_range(analysis::DEFAULT_WINDOW),
+        SAMPLE_RATE as usize,
+    );
+    let mut chunker = Chunker {
+        mono: vec![],
+        left: vec![],
+        right: vec![],
+        stereo: options.channels == 2,
+    };
+    let mut renderer: Option<Renderer> = None;
+    let mut cues = CuePlayer::new(Show {
+        cues: options.cues.clone(),
+        timecode: Timecode::Position,
+    });
+
+    let fps = options.fps as u64;
+    let mut frame = 0;
+    let mut consumed = 0;
+    loop {
+        // integer boundaries keep the video in sync with the audio for every frame rate
+        let needed = ((frame + 1) * SAMPLE_RATE / fps - consumed) as usize;
+        while chunker.mono.len() < needed {
+            match decoder.next() {
+                Some(samples) => chunker.push(samples),
+                None => break,
+            }
+        }
+        if chunker.mono.is_empty() {
+            break;
+        }
+        let (data, stereo) = chunker.take(needed);
+        consumed += data.len() as u64;
+
+        let pts = frame * 1_000_000_000 / fps;
+        let changes = cues.advance(Some(pts));
+        for change in changes.iter() {
+            match *change {
+                Change::Window(window) => analyzer.set_range(analysis::window_range(window)),
+                Change::Agc(enabled) => analyzer.set_agc(enabled),
+                Change::AgcDecay(decay) => analyzer.set_agc_decay(decay),
+                _ => (),
+            }
+        }
+        let spec = analyzer.analyze(Samples {
+            pts: Some(pts),
+            captured: Instant::now(),
+            data: data,
+            stereo: stereo,
+        });
+        if renderer.is_none() {
+            renderer = Some(Renderer::new(&facade, &settings, spec.clone()));
+        }
+        let renderer = renderer.as_mut().unwrap();
+        for change in changes.iter() {
+            renderer.apply(change);
+        }
+        renderer.update(spec, if frame == 0 { 0.0 } else { 1.0 / fps as f32 });
+        renderer.draw(&facade, &mut framebuffer);
+
+        // frames are read bottom up
+        let image: RawImage2d<u8> = texture.read();
+        let row = options.width as usize * 4;
+        let mut data = Vec::with_capacity(image.data.len());
+        for line in image.data.chunks(row).rev() {
+            data.extend_from_slice(line);
+        }
+
+        let mut buffer = match gstreamer::Buffer::from_mut_slice(data) {
+            Some(buffer) => buffer,
+            None => return Err(RenderError::Encode("failed to allocate a frame".to_string()).into()),
+        };
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(gstreamer::ClockTime::from_nseconds(pts));
+            buffer.set_duration(gstreamer::ClockTime::from_nseconds(1_000_000_000 / fps));
+        }
+        if appsrc.push_buffer(buffer) != gstreamer::FlowReturn::Ok {
+            break;
+        }
+        frame += 1;
+    }
+
+    if let Some(e) = decoder.error() {
+        return Err(RenderError::Decode(options.input.clone(), e).into());
+    }
+    appsrc.end_of_stream();
+    finish(&pipeline)?;
+    println!("Rendered {} frames of {}", frame, options.input);
+    Ok(())
+}
@@ -0,0 +1,101 @@
+// Time domain views of the raw samples: the trigger of the oscilloscope and, for stereo
+// input, the XY goniometer with its phase correlation meter.
+//
+// The goniometer plots mid (L + R) against side (L - R) so a mono signal is a vertical line
+// and out of phase channels a horizontal one. Every sample marks its cell of a grid which
+// fades from frame to frame and is drawn as a texture by the shader.
+
+// a crossing only counts after the signal went this far below zero, ignores noise around it
+const TRIGGER_HYSTERESIS: f32 = 0.01;
+
+pub const GRID_SIZE: usize = 256;
+// brightness a sample adds to its cell and the share that is left per frame
+const HIT: f32 = 0.25;
+const DECAY: f32 = 0.85;
+const CORRELATION_SMOOTHING: f32 = 0.8;
+
+// start of the latest window of `window` samples that begins with a rising zero crossing so
+// periodic signals stand still, the most recent window if there is none
+pub fn trigger(samples: &[f32], window: usize) -> usize {
+    let last = samples.len().saturating_sub(window);
+    let mut armed = false;
+    let mut start = None;
+    for (i, &v) in samples[..last + 1].iter().enumerate() {
+        if v < -TRIGGER_HYSTERESIS {
+            armed = true;
+        } else if armed && v >= 0.0 {
+            start = Some(i);
+            armed = false;
+        }
+    }
+    start.unwrap_or(last)
+}
+
+// phase correlation of the channels, 1 for identical, 0 for unrelated and -1 for inverted
+// signals, 0 when either is silent
+pub fn correlation(left: &[f32], right: &[f32]) -> f32 {
+    let (mut lr, mut ll, mut rr) = (0.0, 0.0, 0.0);
+    for (l, r) in left.iter().zip(right.iter()) {
+        lr += l * r;
+        ll += l * l;
+        rr += r * r;
+    }
+    if ll == 0.0 || rr == 0.0 {
+        0.0
+    } else {
+        lr / (ll * rr).sqrt()
+    }
+}
+
+pub struct Goniometer {
+    // brightness of the cells of the mid/side plane, the first row is the bottom one
+    pub grid: Vec<Vec<f32>>,
+    pub correlation: f32,
+}
+
+impl Goniometer {
+    pub fn new() -> Goniometer {
+        Goniometer {
+            grid: vec![vec![0.0; GRID_SIZE]; GRID_SIZE],
+            correlation: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, left: &[f32], right: &[f32]) {
+        for row in self.grid.iter_mut() {
+            for cell in row.iter_mut() {
+                *cell *= DECAY;
+            }
+        }
+
+        let cell = |v: f32| {
+            let i = ((v + 1.0) / 2.0 * GRID_SIZE as f32) as isize;
+            isize::max(0, isize::min(i, GRID_SIZE as isize - 1)) as usize
+        };
+        for (l, r) in left.iter().zip(right.iter()) {
+            let mid = (l + r) / 2.0f32.sqrt();
+            let side = (l - r) / 2.0f32.sqrt();
+            let hit = &mut self.grid[cell(mid)][cell(side)];
+            *hit = f32::min(*hit + HIT, 1.0);
+        }
+
+        self.correlation = CORRELATION_SMOOTHING * self.correlation +
+            (1.0 - CORRELATION_SMOOTHING) * correlation(left, right);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::trigger;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn test_trigger_rising_zero_crossing() {
+        // the window starts at the latest rising zero crossing that leaves room for it
+        let samples: Vec<f32> = (0..400)
+            .map(|i| (2.0 * PI * (i as f32 + 0.5) / 100.0).sin())
+            .collect();
+        assert_eq!(trigger(&samples, 150), 200);
+        assert_eq!(trigger(&[0.5; 300], 150), 150);
+    }
+}
@@ -0,0 +1,82 @@
+// Loads the visualizer shaders from a directory at runtime and notices when they are saved.
+//
+// `<dir>/<name>.glslf` is the fragment shader and `<dir>/<name>.glslv` an optional vertex
+// shader, the built-in vertex shader is used when it does not exist. Presets without a
+// fragment shader in the directory keep their built-in shaders. Modification times are
+// polled, the visualizer checks for changes once per frame anyway.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL_MS: u64 = 250;
+
+pub struct ShaderSource {
+    // `None` when the directory has no vertex shader
+    pub vertex: Option<String>,
+    pub fragment: String,
+}
+
+pub struct ShaderWatcher {
+    vertex_path: PathBuf,
+    fragment_path: PathBuf,
+    // modification times of the vertex and fragment shader when they were last read
+    modified: Option<(Option<SystemTime>, Option<SystemTime>)>,
+    last_poll: Instant,
+}
+
+fn read_file(path: &Path) -> Result<String, io::Error> {
+    let mut source = String::new();
+    File::open(path)?.read_to_string(&mut source)?;
+    Ok(source)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl ShaderWatcher {
+    pub fn new(dir: &Path, name: &str) -> ShaderWatcher {
+        ShaderWatcher {
+            vertex_path: dir.join(format!("{}.glslv", name)),
+            fragment_path: dir.join(format!("{}.glslf", name)),
+            modified: None,
+            last_poll: Instant::now(),
+        }
+    }
+
+    pub fn fragment_path(&self) -> &Path {
+        &self.fragment_path
+    }
+
+    // the sources if they changed since the last call, the first call always reads them
+    pub fn poll(&mut self) -> Option<Result<ShaderSource, io::Error>> {
+        if self.modified.is_some() &&
+            self.last_poll.elapsed() < Duration::from_millis(POLL_INTERVAL_MS)
+        {
+            return None;
+        }
+        self.last_poll = Instant::now();
+
+        let stamps = (modified(&self.vertex_path), modified(&self.fragment_path));
+        if self.modified == Some(stamps) {
+            return None;
+        }
+        self.modified = Some(stamps);
+
+        Some(self.read())
+    }
+
+    fn read(&self) -> Result<ShaderSource, io::Error> {
+        let vertex = if self.vertex_path.exists() {
+            Some(read_file(&self.vertex_path)?)
+        } else {
+            None
+        };
+        Ok(ShaderSource {
+            vertex: vertex,
+            fragment: read_file(&self.fragment_path)?,
+        })
+    }
+}
@@ -0,0 +1,110 @@
+// Lets the visualizer run shaders written for Shadertoy.
+//
+// Shadertoy shaders only define `mainImage(out vec4 fragColor, in vec2 fragCoord)`, their
+// source is wrapped with the declarations of the Shadertoy uniforms and a `main` calling it.
+// The audio is passed the way Shadertoy passes music and microphone input: `iChannel0` is a
+// 512x2 texture, the first row holds the spectrum from 0 to a quarter of the sample rate with
+// the decibels mapped to 0..1, the second row the most recent waveform centered around 0.5.
+
+use glium::backend::Facade;
+use glium::texture::{MipmapsOption, Texture2d, UncompressedFloatFormat};
+use glium;
+
+use simple_decoder::SimpleDecoder;
+use window;
+
+pub const WIDTH: usize = 512;
+// only the lower half of the spectrum is used, like the web audio analyser of Shadertoy
+const FFT_SIZE: usize = 4 * WIDTH;
+
+// mapping and smoothing defaults of the web audio analyser
+const MIN_DECIBELS: f32 = -100.0;
+const MAX_DECIBELS: f32 = -30.0;
+const SMOOTHING: f32 = 0.8;
+const EPSILON: f32 = 1e-12;
+
+const HEADER: &str = "#version 330
+uniform vec3 iResolution;
+uniform float iTime;
+uniform float iTimeDelta;
+uniform int iFrame;
+uniform vec4 iMouse;
+uniform sampler2D iChannel0;
+out vec4 shadertoy_color;
+";
+
+const FOOTER: &str = "
+void main()
+{
+    mainImage(shadertoy_color, gl_FragCoord.xy);
+}
+";
+
+// the fragment shader running `mainImage` of `source`
+pub fn wrap(source: &str) -> String {
+    // keep the line numbers of compile errors in sync with the source
+    format!("{}#line 1\n{}\n{}", HEADER, source, FOOTER)
+}
+
+pub struct AudioInput {
+    pub texture: Texture2d,
+    decoder: SimpleDecoder,
+    // the most recent samples in chronological order
+    samples: Vec<f32>,
+    // smoothed magnitudes of the spectrum row
+    magnitudes: Vec<f32>,
+}
+
+impl AudioInput {
+    pub fn new<F: Facade>(facade: &F, sample_rate: usize) -> AudioInput {
+        let texture = Texture2d::empty_with_format(
+            facade,
+            UncompressedFloatFormat::F32,
+            MipmapsOption::NoMipmap,
+            WIDTH as u32,
+            2,
+        ).unwrap();
+        let mut input = AudioInput {
+            texture: texture,
+            decoder: SimpleDecoder::new(FFT_SIZE, sample_rate),
+            samples: vec![0.0; FFT_SIZE],
+            magnitudes: vec![0.0; WIDTH],
+        };
+        input.upload();
+        input
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        window::slide(&mut self.samples, samples);
+
+        let magnitudes = self.decoder.magnitudes(&self.samples);
+        for (smoothed, m) in self.magnitudes.iter_mut().zip(magnitudes.iter()) {
+            *smoothed = SMOOTHING * *smoothed + (1.0 - SMOOTHING) * m / FFT_SIZE as f32;
+        }
+        self.upload();
+    }
+
+    fn upload(&mut self) {
+        let spectrum: Vec<f32> = self.magnitudes
+            .iter()
+            .map(|m| {
+                let db = 20.0 * (m + EPSILON).log10();
+                ((db - MIN_DECIBELS) / (MAX_DECIBELS - MIN_DECIBELS)).max(0.0).min(1.0)
+            })
+            .collect();
+        let waveform: Vec<f32> = self.samples[self.samples.len() - WIDTH..]
+            .iter()
+            .map(|v| (0.5 + 0.5 * v).max(0.0).min(1.0))
+            .collect();
+        // the first row is the bottom one, sampled by shadertoy shaders at y = 0.25
+        self.texture.write(
+            glium::Rect {
+                left: 0,
+                bottom: 0,
+                width: WIDTH as u32,
+                height: 2,
+            },
+            vec![spectrum, waveform],
+        );
+    }
+}
@@ -17,10 +17,15 @@ pub struct SimpleDecoder {
     fft_out: Vec<c64>,
 }
 
-const PER_OCTAVE: usize = 12;
-const KAMMER_TON: f64 = 440.0;
+pub const PER_OCTAVE: usize = 12;
+pub const KAMMER_TON: f64 = 440.0;
 const LOW_CUT: usize = 20;
 
+// center frequency of the v-th semitone, starting three octaves below KAMMER_TON
+pub fn semitone_freq(v: usize) -> f64 {
+    KAMMER_TON * 2.0_f64.powf(v as f64 / PER_OCTAVE as f64 - 3.0)
+}
+
 impl SimpleDecoder {
     pub fn new_simple() -> SimpleDecoder {
         SimpleDecoder::new(2usize.pow(14), 44100)
@@ -31,8 +36,7 @@ impl SimpleDecoder {
         let num_outputs = 7 * PER_OCTAVE; // FIXME
         let complex_freqs = (0..num_outputs)
             .map(|v| {
-                (KAMMER_TON * 2.0_f64.powf(v as f64 / PER_OCTAVE as f64 - 3.0) /
-                     sample_rate as f64 * sample_count as f64) as usize
+                (semitone_freq(v) / sample_rate as f64 * sample_count as f64) as usize
             })
             .filter(|&v| v < sample_count / 2) // Only the lower half of the result buffer contains the meaningful frequencies in the range(0, 22kHz).
             .collect();
@@ -52,7 +56,7 @@ impl SimpleDecoder {
     }
 
 
-    pub fn decode(&mut self, input: &[f32]) -> Vec<f32> {
+    fn transform(&mut self, input: &[f32]) {
         // apply windowing
         assert_eq!(input.len(), self.sample_count);
 
@@ -62,6 +66,19 @@ impl SimpleDecoder {
         }
         // apply fft
         self.fft.process(&mut self.fft_in, &mut self.fft_out);
+    }
+
+    // magnitude of every fft bin up to the nyquist frequency, bin i is at i * sample_rate / sample_count
+    pub fn magnitudes(&mut self, input: &[f32]) -> Vec<f32> {
+        self.transform(input);
+        self.fft_out[..self.sample_count / 2]
+            .iter()
+            .map(|val| val.norm() as f32)
+            .collect()
+    }
+
+    pub fn decode(&mut self, input: &[f32]) -> Vec<f32> {
+        self.transform(input);
 
         // collect peak magnitude at each frequency
         let mut spectrum = vec![0.0 as f32; self.freqs.len()];
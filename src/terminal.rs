@@ -0,0 +1,186 @@
+// Spectrum display in the terminal, e.g. to check the input of a machine without a display
+// over SSH.
+//
+// The bins are drawn as bars of Unicode blocks with eighth steps, green at the bottom to red
+// at the top. They are spread over the width of the terminal or grouped into fewer bars when
+// it is narrower than the spectrum. A status line below shows a beat indicator, the peak
+// level and the loudness, beats of frames skipped because of `--terminal-fps` light it up
+// as well. The width is queried from the terminal again whenever it is resized. Anything
+// else printed, like the sink statistics of `--stats-interval`, scrolls the display.
+
+use std::env;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc;
+
+use bands;
+use broadcast;
+use frame::SpectrumFrame;
+
+const BLOCKS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const DEFAULT_WIDTH: usize = 80;
+// frames the beat indicator stays lit
+const BEAT_HOLD: u32 = 4;
+// lowest level shown by the level meter
+const MIN_DB: f32 = -60.0;
+
+const RESET: &str = "\x1b[0m";
+
+// set by SIGWINCH when the terminal has been resized
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_resize(_: libc::c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+// columns of the terminal on stdout
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { mem::zeroed() };
+    let ioctl = if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 {
+        Some(size.ws_col as usize)
+    } else {
+        None
+    };
+    match ioctl.or_else(|| env::var("COLUMNS").ok().and_then(|c| c.parse().ok())) {
+        Some(columns) if columns > 0 => columns,
+        _ => DEFAULT_WIDTH,
+    }
+}
+
+// green at the bottom, yellow in the middle and red at the top
+fn color(row: usize, rows: usize) -> &'static str {
+    if row * 4 >= rows * 3 {
+        "\x1b[31m"
+    } else if row * 2 >= rows {
+        "\x1b[33m"
+    } else {
+        "\x1b[32m"
+    }
+}
+
+// the block of a bar of height `value` (0 to 1) in row `row` of `rows`, counted from the bottom
+fn block(value: f32, row: usize, rows: usize) -> char {
+    let eighths = (value.abs().min(1.0) * (rows * 8) as f32).round() as usize;
+    let filled = eighths.saturating_sub(row * 8);
+    BLOCKS[usize::min(filled, 8)]
+}
+
+// one value per column of a terminal `width` columns wide
+fn columns(bins: &[f32], width: usize) -> Vec<f32> {
+    if bins.is_empty() || width == 0 {
+        return vec![];
+    }
+    if width < bins.len() {
+        return bands::group(bins, width);
+    }
+    // every bin gets as many columns as fit, the rest of the line stays empty
+    let per_bin = width / bins.len();
+    bins.iter()
+        .flat_map(|&v| ::std::iter::repeat(v).take(per_bin))
+        .collect()
+}
+
+fn level_db(level: f32) -> f32 {
+    if level > 0.0 {
+        f32::max(20.0 * level.log10(), MIN_DB)
+    } else {
+        MIN_DB
+    }
+}
+
+fn status(frame: &SpectrumFrame, beat: bool, width: usize) -> String {
+    let peak = level_db(frame.peak);
+    let text = format!(
+        " peak {:6.1} dBFS  {:6.1} LUFS  {:<4} ",
+        peak,
+        frame.loudness.momentary,
+        frame.pitch.as_ref().map(|p| p.name()).unwrap_or_default()
+    );
+    let mut line = if beat {
+        format!("\x1b[1;41m BEAT {}{}", RESET, text)
+    } else {
+        format!("      {}", text)
+    };
+    // the rest of the line is a meter of the peak level
+    let meter = width.saturating_sub(6 + text.chars().count() + 2);
+    if meter > 0 {
+        let lit = ((peak - MIN_DB) / -MIN_DB * meter as f32).round() as usize;
+        line.push('[');
+        line.push_str(if peak > -1.0 { "\x1b[31m" } else { "\x1b[32m" });
+        line.extend(::std::iter::repeat('|').take(usize::min(lit, meter)));
+        line.push_str(RESET);
+        line.extend(::std::iter::repeat(' ').take(meter.saturating_sub(lit)));
+        line.push(']');
+    }
+    line
+}
+
+// the whole screen for a frame, the cursor is moved to the top left first so the previous
+// frame is overwritten in place
+fn render(frame: &SpectrumFrame, beat: bool, width: usize, rows: usize) -> String {
+    let values = columns(&frame.bins, width);
+    let mut screen = String::from("\x1b[H");
+    for row in (0..rows).rev() {
+        screen.push_str(color(row, rows));
+        screen.extend(values.iter().map(|&v| block(v, row, rows)));
+        screen.push_str(RESET);
+        // clear what is left of a previous, wider line
+        screen.push_str("\x1b[K\n");
+    }
+    screen.push_str(&status(frame, beat, width));
+    screen.push_str("\x1b[K");
+    screen
+}
+
+pub fn terminal(rows: usize, mut rx: broadcast::Receiver<SpectrumFrame>) {
+    let stdout = io::stdout();
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = on_resize;
+        libc::signal(libc::SIGWINCH, handler as libc::sighandler_t);
+    }
+    let mut width = terminal_width();
+    let mut beat_hold = 0;
+
+    print!("\x1b[2J");
+    while let Ok(frame) = rx.recv() {
+        if RESIZED.swap(false, Ordering::SeqCst) {
+            let new_width = terminal_width();
+            if new_width != width {
+                print!("\x1b[2J");
+                width = new_width;
+            }
+        }
+
+        beat_hold = if frame.beat {
+            BEAT_HOLD
+        } else {
+            beat_hold.saturating_sub(1)
+        };
+        let screen = render(&frame, beat_hold > 0, width, rows);
+        let mut out = stdout.lock();
+        if out.write_all(screen.as_bytes()).and_then(|_| out.flush()).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block, columns};
+
+    #[test]
+    fn test_bars_fit_the_width() {
+        let bins = vec![0.5; 84];
+        assert_eq!(columns(&bins, 40).len(), 40);
+        assert_eq!(columns(&bins, 84).len(), 84);
+        assert_eq!(columns(&bins, 200).len(), 168);
+
+        // half of four rows is two full rows
+        assert_eq!(block(0.5, 0, 4), '█');
+        assert_eq!(block(0.5, 1, 4), '█');
+        assert_eq!(block(0.5, 2, 4), ' ');
+        assert_eq!(block(0.625, 2, 4), '▄');
+    }
+}
@@ -0,0 +1,286 @@
+// Minimal text rendering for the visualizer, e.g. to show shader compile errors.
+//
+// Lines are rasterized on the CPU with a built-in 5x7 pixel font into a texture which is
+// drawn as a single quad on top of the scene. Only printable ASCII is supported, everything
+// else is drawn as '?'.
+
+use glium::texture::{MipmapsOption, Texture2d, UncompressedFloatFormat};
+use glium::backend::Facade;
+use glium::{Blend, DrawParameters, Program, Surface, VertexBuffer};
+use glium::index::{NoIndices, PrimitiveType};
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+// one pixel of space between characters and lines and around the text
+const ADVANCE: usize = GLYPH_WIDTH + 1;
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 2;
+const PADDING: usize = 2;
+
+const FIRST_CHAR: u8 = b' ';
+
+// one byte per column from left to right, the lowest bit is the top row
+const FONT: [[u8; GLYPH_WIDTH]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // #
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // )
+    [0x08, 0x2a, 0x1c, 0x2a, 0x08], // *
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // 0
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4b, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3c, 0x4a, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1e], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3e], // @
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], // A
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], // D
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7f, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3e, 0x41, 0x49, 0x49, 0x7a], // G
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // H
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // J
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f], // M
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // N
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // O
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // Q
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7f, 0x01, 0x01], // T
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // U
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // V
+    [0x3f, 0x40, 0x38, 0x40, 0x3f], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7f, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7f, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7f, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7f], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7e, 0x09, 0x01, 0x02], // f
+    [0x0c, 0x52, 0x52, 0x52, 0x3e], // g
+    [0x7f, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7d, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3d, 0x00], // j
+    [0x7f, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7f, 0x40, 0x00], // l
+    [0x7c, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7c, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7c, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7c], // q
+    [0x7c, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3f, 0x44, 0x40, 0x20], // t
+    [0x3c, 0x40, 0x40, 0x20, 0x7c], // u
+    [0x1c, 0x20, 0x40, 0x20, 0x1c], // v
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0c, 0x50, 0x50, 0x50, 0x3c], // y
+    [0x44, 0x64, 0x54, 0x4c, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7f, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x08, 0x04, 0x08, 0x10, 0x08], // ~
+];
+
+const VERTEX_SHADER: &str = "
+#version 330
+in vec2 position;
+in vec2 tex_coords;
+out vec2 v_tex_coords;
+
+void main()
+{
+    gl_Position = vec4(position, 0.0, 1.0);
+    v_tex_coords = tex_coords;
+}
+";
+
+const FRAGMENT_SHADER: &str = "
+#version 330
+uniform sampler2D glyphs;
+uniform vec4 color;
+in vec2 v_tex_coords;
+out vec4 f_color;
+
+void main()
+{
+    ivec2 size = textureSize(glyphs, 0);
+    ivec2 texel = min(ivec2(v_tex_coords * vec2(size)), size - 1);
+    float lit = texelFetch(glyphs, texel, 0).r;
+    f_color = mix(vec4(0.0, 0.0, 0.0, 0.7), color, lit);
+}
+";
+
+#[derive(Copy, Clone)]
+struct Vertex {
+    position: [f32; 2],
+    tex_coords: [f32; 2],
+}
+
+implement_vertex!(Vertex, position, tex_coords);
+
+fn glyph(c: char) -> &'static [u8; GLYPH_WIDTH] {
+    let index = if c.is_ascii() && !c.is_ascii_control() {
+        c as u8 - FIRST_CHAR
+    } else {
+        b'?' - FIRST_CHAR
+    };
+    &FONT[index as usize]
+}
+
+// the pixels of the text, top row first, 255 where a glyph is lit
+fn rasterize(lines: &[String]) -> Vec<Vec<u8>> {
+    let columns = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    let width = columns * ADVANCE + 2 * PADDING;
+    let height = lines.len() * LINE_HEIGHT + 2 * PADDING;
+    let mut pixels = vec![vec![0u8; width]; height];
+    for (l, line) in lines.iter().enumerate() {
+        for (c, ch) in line.chars().enumerate() {
+            for (x, column) in glyph(ch).iter().enumerate() {
+                for y in 0..GLYPH_HEIGHT {
+                    if *column >> y & 1 == 1 {
+                        pixels[PADDING + l * LINE_HEIGHT + y][PADDING + c * ADVANCE + x] = 255;
+                    }
+                }
+            }
+        }
+    }
+    pixels
+}
+
+// split `text` into lines of at most `columns` characters
+pub fn wrap(text: &str, columns: usize) -> Vec<String> {
+    let columns = usize::max(columns, 1);
+    let mut lines = vec![];
+    for line in text.lines() {
+        let chars: Vec<char> = line.replace('\t', "    ").chars().collect();
+        if chars.is_empty() {
+            lines.push(String::new());
+        }
+        for chunk in chars.chunks(columns) {
+            lines.push(chunk.iter().collect());
+        }
+    }
+    lines
+}
+
+// number of characters that fit next to each other on a surface `width` pixels wide
+pub fn columns(width: u32, scale: u32) -> usize {
+    (width as usize / scale as usize).saturating_sub(2 * PADDING) / ADVANCE
+}
+
+// size in pixels of `lines` drawn at `scale`, including the background
+pub fn size(lines: &[String], scale: u32) -> (u32, u32) {
+    let columns = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    (
+        ((columns * ADVANCE + 2 * PADDING) as u32) * scale,
+        ((lines.len() * LINE_HEIGHT + 2 * PADDING) as u32) * scale,
+    )
+}
+
+pub struct TextRenderer {
+    program: Program,
+}
+
+impl TextRenderer {
+    pub fn new<F: Facade>(facade: &F) -> TextRenderer {
+        TextRenderer {
+            program: Program::from_source(facade, VERTEX_SHADER, FRAGMENT_SHADER, None).unwrap(),
+        }
+    }
+
+    // draw `lines` on a dark background with the top left corner at `x`, `y` pixels from the
+    // top left of the target, every font pixel covering `scale` x `scale` pixels
+    pub fn draw<F: Facade, S: Surface>(
+        &self,
+        facade: &F,
+        target: &mut S,
+        lines: &[String],
+        (x, y): (u32, u32),
+        scale: u32,
+        color: [f32; 4],
+    ) {
+        if lines.is_empty() {
+            return;
+        }
+        let mut pixels = rasterize(lines);
+        let (width, height) = (pixels[0].len(), pixels.len());
+        // textures start with the bottom row
+        pixels.reverse();
+        let texture = match Texture2d::with_format(
+            facade,
+            pixels,
+            UncompressedFloatFormat::U8,
+            MipmapsOption::NoMipmap,
+        ) {
+            Ok(texture) => texture,
+            Err(_) => return,
+        };
+
+        let (target_width, target_height) = target.get_dimensions();
+        let left = -1.0 + 2.0 * x as f32 / target_width as f32;
+        let right = left + 2.0 * (width as u32 * scale) as f32 / target_width as f32;
+        let top = 1.0 - 2.0 * y as f32 / target_height as f32;
+        let bottom = top - 2.0 * (height as u32 * scale) as f32 / target_height as f32;
+        let quad = [
+            Vertex { position: [left, bottom], tex_coords: [0.0, 0.0] },
+            Vertex { position: [left, top], tex_coords: [0.0, 1.0] },
+            Vertex { position: [right, bottom], tex_coords: [1.0, 0.0] },
+            Vertex { position: [right, top], tex_coords: [1.0, 1.0] },
+        ];
+        let vertex_buffer = match VertexBuffer::new(facade, &quad) {
+            Ok(buffer) => buffer,
+            Err(_) => return,
+        };
+
+        let parameters = DrawParameters {
+            blend: Blend::alpha_blending(),
+            ..Default::default()
+        };
+        let _ = target.draw(
+            &vertex_buffer,
+            &NoIndices(PrimitiveType::TriangleStrip),
+            &self.program,
+            &uniform!{
+                glyphs: &texture,
+                color: color,
+            },
+            &parameters,
+        );
+    }
+}
@@ -1,82 +1,977 @@
-use std::sync::mpsc::Receiver;
+// The OpenGL visualizer.
+//
+// Every mode is a preset with its own fragment shader. Keys:
+//   Left/Right  previous/next preset
+//   A           toggle automatic preset rotation on phrase boundaries
+//   Space       pause
+//   Up/Down     raise/lower the gain
+//   F           toggle fullscreen on the configured monitor, Escape leaves it
+//   O           toggle the overlay with frequency labels, levels and the frame rate
+//
+// Every window shows its own preset and takes its own keys. The shaders get the size of the
+// framebuffer in pixels as `resolution`, its `aspect` ratio and the HiDPI `pixel_ratio`.
+//
+// The windows are redrawn on every vsync, independent of the rate of the analysis. The bins
+// are interpolated from the previous to the latest frame over the time between frames, which
+// delays them by one frame but keeps the motion smooth at any refresh rate. The windows stay
+// black until the first frame arrives.
+//
+// The oscilloscope and goniometer presets draw the raw samples of the frames, the goniometer
+// needs stereo input to show more than a vertical line.
+
 use glium::Surface;
+use glium::backend::Facade;
 use glium::glutin::WindowBuilder;
 use glium::glutin;
+use glium::texture::{MipmapsOption, Texture2d, TextureCreationError, UncompressedFloatFormat};
+use glium::uniforms::SamplerWrapFunction;
 use glium;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::TryRecvError;
+use std::thread::sleep;
 use std::time;
 
-pub fn visual(spec_rx: Receiver<Vec<f32>>) {
-    use glium::texture::buffer_texture::BufferTexture;
-    use glium::texture::buffer_texture::BufferTextureType;
+use broadcast;
+use control::Watcher;
+use cues::{Change, CuePlayer, Show};
+use frame::SpectrumFrame;
+use overlay::{Axis, Overlay};
+use shaders::{ShaderSource, ShaderWatcher};
+use scope::{self, Goniometer};
+use shadertoy::{self, AudioInput};
+use text::{self, TextRenderer};
+use window;
 
-    let time  = time::Instant::now();
-    let window = WindowBuilder::new()
-        .with_title("soundvis".to_string())
-        .with_dimensions(1024, 786);
-    let mut events_loop = glutin::EventsLoop::new();
-    let context = glutin::ContextBuilder::new();
-    let display = glium::Display::new(window, context, &events_loop).unwrap();
-    let program = glium::Program::from_source(
-        &display,
-        include_str!("../default.glslv"),
-        include_str!("../default.glslf"),
-        None,
-    ).unwrap();
+// font pixels are drawn as blocks of this many pixels
+const ERROR_SCALE: u32 = 2;
+
+// number of samples passed to the shaders as `waveform`, twice as many are kept to find a
+// trigger point
+const WAVEFORM_SAMPLES: usize = 1024;
+
+// beats per preset when the rotation is turned on by key without a configured length
+const PHRASE_BEATS: u32 = 32;
+
+// factor by which the gain changes per key press
+const GAIN_STEP: f32 = 1.25;
+
+// weight of the previous estimate of the time between two analysis frames
+const INTERVAL_SMOOTHING: f32 = 0.9;
+// upper bound of the frame rate of the windows in case the driver ignores vsync
+const MAX_FPS: u32 = 240;
+
+const DEFAULT_VERTEX_SHADER: &str = include_str!("../default.glslv");
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mode {
+    // bars of the current frame
+    Spectrum,
+    // the bars of the current frame arranged in a circle
+    Radial,
+    // the most recent samples, triggered on rising zero crossings
+    Oscilloscope,
+    // the stereo channels against each other and their phase correlation
+    Goniometer,
+    // scrolling time-frequency view of the recent frames
+    Waterfall,
+    // particles driven by the bands and beats
+    Particles,
+    // shaders written for shadertoy, see `shadertoy`
+    Shadertoy,
+}
+
+// the order the presets are cycled through
+const PRESETS: [Mode; 7] = [
+    Mode::Spectrum,
+    Mode::Radial,
+    Mode::Oscilloscope,
+    Mode::Goniometer,
+    Mode::Waterfall,
+    Mode::Particles,
+    Mode::Shadertoy,
+];
+
+impl Mode {
+    // the name it is parsed from
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Mode::Spectrum => "spectrum",
+            Mode::Radial => "radial",
+            Mode::Oscilloscope => "oscilloscope",
+            Mode::Goniometer => "goniometer",
+            Mode::Waterfall => "waterfall",
+            Mode::Particles => "particles",
+            Mode::Shadertoy => "shadertoy",
+        }
+    }
+
+    // where the built-in shader puts the bins, `None` if it has no frequency axis
+    fn axis(&self) -> Option<Axis> {
+        match *self {
+            Mode::Spectrum => Some(Axis::Mirrored),
+            Mode::Waterfall => Some(Axis::Linear),
+            _ => None,
+        }
+    }
+
+    // the name the shader is looked up by in the shader directory and its built-in source
+    fn shader(&self) -> (&'static str, &'static str) {
+        match *self {
+            Mode::Spectrum => ("default", include_str!("../default.glslf")),
+            Mode::Radial => ("radial", include_str!("../radial.glslf")),
+            Mode::Oscilloscope => ("oscilloscope", include_str!("../oscilloscope.glslf")),
+            Mode::Goniometer => ("goniometer", include_str!("../goniometer.glslf")),
+            Mode::Waterfall => ("spectrogram", include_str!("../spectrogram.glslf")),
+            Mode::Particles => ("particles", include_str!("../particles.glslf")),
+            Mode::Shadertoy => ("shadertoy", include_str!("../shadertoy.glslf")),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "spectrum" | "bars" => Ok(Mode::Spectrum),
+            "radial" => Ok(Mode::Radial),
+            "oscilloscope" => Ok(Mode::Oscilloscope),
+            "goniometer" => Ok(Mode::Goniometer),
+            "waterfall" => Ok(Mode::Waterfall),
+            "particles" => Ok(Mode::Particles),
+            "shadertoy" => Ok(Mode::Shadertoy),
+            _ => Err(()),
+        }
+    }
+}
+
+// the numbers are passed to the shader as `colormap`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Colormap {
+    Viridis = 0,
+    Magma = 1,
+    Grayscale = 2,
+}
+
+impl FromStr for Colormap {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "viridis" => Ok(Colormap::Viridis),
+            "magma" => Ok(Colormap::Magma),
+            "grayscale" => Ok(Colormap::Grayscale),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Amplitude {
+    Linear,
+    // decibels, 60dB of dynamic range
+    Log,
+}
+
+impl FromStr for Amplitude {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "linear" => Ok(Amplitude::Linear),
+            "log" => Ok(Amplitude::Log),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Settings {
+    // the preset shown first
+    pub mode: Mode,
+    // size of new windows in logical pixels
+    pub size: (u32, u32),
+    // index of the monitor used for fullscreen, `None` for the primary one
+    pub monitor: Option<usize>,
+    pub fullscreen: bool,
+    // show the overlay from the start
+    pub overlay: bool,
+    // number of frames shown by the waterfall
+    pub history: usize,
+    pub colormap: Colormap,
+    pub amplitude: Amplitude,
+    // directory the shaders are loaded from and reloaded when they change, `None` only
+    // uses the built-in ones
+    pub shader_dir: Option<PathBuf>,
+    // name of the shader of the first preset in `shader_dir`, defaults to the one of the mode
+    pub shader: Option<String>,
+    // switch to the next preset after this many beats, `None` disables the rotation
+    pub rotate_beats: Option<u32>,
+}
+
+// ring buffer of the most recent frames stored as rows of a texture
+struct History {
+    texture: Texture2d,
+    width: usize,
+    rows: usize,
+    newest: usize,
+}
+
+impl History {
+    fn new<F: Facade>(facade: &F, width: usize, history: usize) -> History {
+        let mut rows = history;
+        let texture = loop {
+            match Texture2d::empty_with_format(
+                facade,
+                UncompressedFloatFormat::F32,
+                MipmapsOption::NoMipmap,
+                width as u32,
+                rows as u32,
+            ) {
+                // taller than the largest texture of the context
+                Err(TextureCreationError::DimensionsNotSupported) if rows > 1 => rows /= 2,
+                result => break result.unwrap(),
+            }
+        };
+        if rows < history {
+            println!(
+                "The waterfall history is limited to {} rows by the graphics driver",
+                rows
+            );
+        }
+        texture.write(
+            glium::Rect {
+                left: 0,
+                bottom: 0,
+                width: width as u32,
+                height: rows as u32,
+            },
+            vec![vec![0.0f32; width]; rows],
+        );
+        History {
+            texture: texture,
+            width: width,
+            rows: rows,
+            newest: 0,
+        }
+    }
 
-    #[derive(Copy, Clone)]
-    struct Vertex {
-        position: [f32; 2],
+    fn push(&mut self, bins: &[f32]) {
+        self.newest = (self.newest + 1) % self.rows;
+        let mut row = bins.to_vec();
+        row.resize(self.width, 0.0);
+        self.texture.write(
+            glium::Rect {
+                left: 0,
+                bottom: self.newest as u32,
+                width: self.width as u32,
+                height: 1,
+            },
+            vec![row],
+        );
     }
+}
 
-    implement_vertex!(Vertex, position);
+fn compile<F: Facade>(
+    facade: &F,
+    mode: Mode,
+    source: &ShaderSource,
+) -> Result<glium::Program, String> {
+    use glium::program::ProgramCreationError;
 
-    let shape = vec![
-          Vertex { position: [ -1.0, -1.0]}, //-1.0, -1.0,  0.5,  0.0, ] },
-          Vertex { position: [ -1.0, 1.0]}, // 0.0, -1.0,  1.0,  0.5, ] },
-          Vertex { position: [ 1.0, -1.0]}, // 0.0,  1.0,  1.0, -1.0, ] },
+    let vertex = source.vertex.as_ref().map(|s| s.as_str()).unwrap_or(DEFAULT_VERTEX_SHADER);
+    let fragment = match mode {
+        Mode::Shadertoy => shadertoy::wrap(&source.fragment),
+        _ => source.fragment.clone(),
+    };
+    glium::Program::from_source(facade, vertex, &fragment, None).map_err(|e| match e {
+        ProgramCreationError::CompilationError(log) |
+        ProgramCreationError::LinkingError(log) => log,
+        e => e.to_string(),
+    })
+}
 
+// the shaders compiled into the binary
+fn builtin(mode: Mode) -> ShaderSource {
+    ShaderSource {
+        vertex: None,
+        fragment: mode.shader().1.to_string(),
+    }
+}
 
-          Vertex { position: [ -1.0, 1.0]}, // 0.0, -1.0,  1.0,  0.5, ] },
-          Vertex { position: [ 1.0, 1.0]}, // 0.0, -1.0,  1.0,  0.5, ] },
-          Vertex { position: [ 1.0, -1.0]}, //-1.0, -1.0,  0.5,  0.0, ] },
+// a preset with the program that draws it
+struct Scene {
+    mode: Mode,
+    name: String,
+    program: glium::Program,
+    watcher: Option<ShaderWatcher>,
+    // the program was compiled from the shader directory
+    custom: bool,
+    // shown on top of the scene while the last working program keeps running
+    error: Option<String>,
+}
 
-    ];
+impl Scene {
+    fn new<F: Facade>(facade: &F, settings: &Settings, mode: Mode) -> Scene {
+        let (default_name, _) = mode.shader();
+        let name = match settings.shader {
+            Some(ref name) if mode == settings.mode => name.clone(),
+            _ => default_name.to_string(),
+        };
+        Scene {
+            mode: mode,
+            program: compile(facade, mode, &builtin(mode)).unwrap(),
+            watcher: settings
+                .shader_dir
+                .as_ref()
+                .map(|dir| ShaderWatcher::new(dir, &name)),
+            custom: false,
+            name: name,
+            error: None,
+        }
+    }
 
-    let vertex_buffer = glium::VertexBuffer::new(&display, &shape).unwrap();
-    let mut spec = spec_rx.recv().unwrap();
-    loop {
-        let elapsed = time.elapsed();
-        let t = (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1000000000.0) as f32;
+    // recompile the shader if it was changed in the shader directory
+    fn reload<F: Facade>(&mut self, facade: &F) {
+        let watcher = match self.watcher {
+            Some(ref mut watcher) => watcher,
+            None => return,
+        };
+        let source = watcher.poll();
+        let path = watcher.fragment_path().display();
+        match source {
+            Some(Ok(source)) => match compile(facade, self.mode, &source) {
+                Ok(p) => {
+                    self.program = p;
+                    self.custom = true;
+                    self.error = None;
+                }
+                Err(e) => self.error = Some(format!("{}:\n{}", path, e)),
+            },
+            // the directory doesn't override this preset (anymore)
+            Some(Err(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                if self.custom {
+                    self.program = compile(facade, self.mode, &builtin(self.mode)).unwrap();
+                    self.custom = false;
+                }
+                self.error = None;
+            }
+            Some(Err(e)) => self.error = Some(format!("{}: {}", path, e)),
+            None => (),
+        }
+    }
+}
 
-        let buf_tex = BufferTexture::new(&display, &spec, BufferTextureType::Float);
+// scale the spectrum and the samples of the frame by `gain`
+fn apply_gain(frame: &mut SpectrumFrame, gain: f32) {
+    for v in frame.bins.iter_mut().chain(frame.samples.iter_mut()) {
+        *v *= gain;
+    }
+    if let Some(ref mut stereo) = frame.stereo {
+        for v in stereo.left.iter_mut().chain(stereo.right.iter_mut()) {
+            *v *= gain;
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Vertex {
+    position: [f32; 2],
+}
+
+implement_vertex!(Vertex, position);
+
+// the presets and everything they are drawn from, used by the window as well as the
+// headless renderer
+pub struct Renderer {
+    scenes: Vec<Scene>,
+    current: usize,
+    vertex_buffer: glium::VertexBuffer<Vertex>,
+    text: TextRenderer,
+    overlay: Overlay,
+    colormap: Colormap,
+    amplitude: Amplitude,
+    spec: SpectrumFrame,
+    // bins drawn, moving from `previous_bins` towards the bins of `spec`
+    bins: Vec<f32>,
+    previous_bins: Vec<f32>,
+    // seconds since the latest frame and the estimated time between frames
+    since_frame: f32,
+    frame_interval: f32,
+    history: History,
+    audio: AudioInput,
+    recent_samples: Vec<f32>,
+    goniometer: Goniometer,
+    xy_scope: Texture2d,
+    // animation time in seconds and the step of the last update
+    time: f32,
+    time_delta: f32,
+    frame_count: i32,
+    // seconds since the last beat
+    beat_age: f32,
+    beats: u32,
+    beats_per_preset: u32,
+    // switch presets on phrase boundaries
+    rotate: bool,
+    pub gain: f32,
+    // shadertoy mouse in pixels from the bottom left: xy while a button is held, zw where it
+    // was pressed, negated once it is released
+    pub mouse: [f32; 4],
+    // physical pixels per logical pixel
+    pub pixel_ratio: f32,
+}
+
+impl Renderer {
+    pub fn new<F: Facade>(facade: &F, settings: &Settings, first: SpectrumFrame) -> Renderer {
+        let shape = vec![
+              Vertex { position: [ -1.0, -1.0]}, //-1.0, -1.0,  0.5,  0.0, ] },
+              Vertex { position: [ -1.0, 1.0]}, // 0.0, -1.0,  1.0,  0.5, ] },
+              Vertex { position: [ 1.0, -1.0]}, // 0.0,  1.0,  1.0, -1.0, ] },
+
+
+              Vertex { position: [ -1.0, 1.0]}, // 0.0, -1.0,  1.0,  0.5, ] },
+              Vertex { position: [ 1.0, 1.0]}, // 0.0, -1.0,  1.0,  0.5, ] },
+              Vertex { position: [ 1.0, -1.0]}, //-1.0, -1.0,  0.5,  0.0, ] },
+
+        ];
+
+        Renderer {
+            scenes: PRESETS
+                .iter()
+                .map(|&mode| Scene::new(facade, settings, mode))
+                .collect(),
+            current: PRESETS.iter().position(|&m| m == settings.mode).unwrap_or(0),
+            vertex_buffer: glium::VertexBuffer::new(facade, &shape).unwrap(),
+            text: TextRenderer::new(facade),
+            overlay: Overlay::new(facade, settings.overlay),
+            colormap: settings.colormap,
+            amplitude: settings.amplitude,
+            history: History::new(facade, first.bins.len(), settings.history),
+            audio: AudioInput::new(facade, first.sample_rate),
+            bins: first.bins.clone(),
+            previous_bins: first.bins.clone(),
+            since_frame: 0.0,
+            frame_interval: 0.0,
+            spec: first,
+            recent_samples: vec![0.0; 2 * WAVEFORM_SAMPLES],
+            goniometer: Goniometer::new(),
+            xy_scope: Texture2d::empty_with_format(
+                facade,
+                UncompressedFloatFormat::F32,
+                MipmapsOption::NoMipmap,
+                scope::GRID_SIZE as u32,
+                scope::GRID_SIZE as u32,
+            ).unwrap(),
+            time: 0.0,
+            time_delta: 0.0,
+            frame_count: 0,
+            beat_age: 0.0,
+            beats: 0,
+            beats_per_preset: settings.rotate_beats.unwrap_or(PHRASE_BEATS),
+            rotate: settings.rotate_beats.is_some(),
+            gain: 1.0,
+            mouse: [0.0; 4],
+            pixel_ratio: 1.0,
+        }
+    }
+
+    pub fn scene_name(&self) -> &str {
+        &self.scenes[self.current].name
+    }
+
+    // go `step` presets forward, or backward for negative steps
+    pub fn switch(&mut self, step: isize) {
+        let count = self.scenes.len() as isize;
+        self.current = ((self.current as isize + step) % count + count) as usize % self.scenes.len();
+        self.beats = 0;
+    }
+
+    // apply a change of a cue or the control API, the ones for the analysis and the LEDs
+    // are ignored
+    pub fn apply(&mut self, change: &Change) {
+        match *change {
+            Change::Preset(mode) => {
+                if let Some(index) = self.scenes.iter().position(|s| s.mode == mode) {
+                    self.current = index;
+                    self.beats = 0;
+                }
+            }
+            Change::Gain(gain) => self.gain = gain,
+            Change::Rotate(rotate) => {
+                self.rotate = rotate;
+                self.beats = 0;
+            }
+            Change::Overlay(visible) => self.overlay.visible = visible,
+            _ => (),
+        }
+    }
+
+    pub fn toggle_overlay(&mut self) {
+        self.overlay.visible = !self.overlay.visible;
+    }
+
+    pub fn toggle_rotation(&mut self) {
+        self.rotate = !self.rotate;
+        self.beats = 0;
+    }
+
+    // take in the next frame and advance the animation by `dt` seconds
+    pub fn update(&mut self, spec: SpectrumFrame, dt: f32) {
+        self.push(spec);
+        self.advance(dt);
+    }
+
+    // take in the next frame of the analysis
+    pub fn push(&mut self, mut spec: SpectrumFrame) {
+        apply_gain(&mut spec, self.gain);
+        self.history.push(&spec.bins);
+        self.audio.push(&spec.samples);
+
+        window::slide(&mut self.recent_samples, &spec.samples);
+
+        match spec.stereo {
+            Some(ref stereo) => self.goniometer.push(&stereo.left, &stereo.right),
+            None => self.goniometer.push(&spec.samples, &spec.samples),
+        }
+        self.xy_scope.write(
+            glium::Rect {
+                left: 0,
+                bottom: 0,
+                width: scope::GRID_SIZE as u32,
+                height: scope::GRID_SIZE as u32,
+            },
+            self.goniometer.grid.clone(),
+        );
+
+        if spec.beat {
+            self.beat_age = 0.0;
+            self.beats += 1;
+            if self.rotate && self.beats % self.beats_per_preset == 0 {
+                self.current = (self.current + 1) % self.scenes.len();
+            }
+        }
+
+        if self.since_frame > 0.0 {
+            self.frame_interval = INTERVAL_SMOOTHING * self.frame_interval +
+                (1.0 - INTERVAL_SMOOTHING) * self.since_frame;
+        }
+        self.since_frame = 0.0;
+        // start from what is on screen so a frame arriving early does not jump
+        self.previous_bins = self.bins.clone();
+        self.spec = spec;
+    }
+
+    // advance the animation by `dt` seconds
+    pub fn advance(&mut self, dt: f32) {
+        self.time += dt;
+        self.time_delta = dt;
+        self.beat_age += dt;
+        self.since_frame += dt;
+        self.overlay.tick(dt);
+
+        let t = if self.frame_interval > 0.0 {
+            f32::min(self.since_frame / self.frame_interval, 1.0)
+        } else {
+            1.0
+        };
+        self.bins = if self.previous_bins.len() == self.spec.bins.len() {
+            self.previous_bins
+                .iter()
+                .zip(self.spec.bins.iter())
+                .map(|(a, b)| a + (b - a) * t)
+                .collect()
+        } else {
+            self.spec.bins.clone()
+        };
+    }
+
+    pub fn draw<F: Facade, S: Surface>(&mut self, facade: &F, target: &mut S) {
+        use glium::texture::buffer_texture::BufferTexture;
+        use glium::texture::buffer_texture::BufferTextureType;
+
+        let (width, height) = target.get_dimensions();
+        // minimized windows have no pixels and no aspect ratio
+        if width == 0 || height == 0 {
+            return;
+        }
+        let scene = &mut self.scenes[self.current];
+        scene.reload(facade);
+
+        let spec = &self.spec;
+        let nyquist = spec.sample_rate as f32 / 2.0;
+
+        let buf_tex = BufferTexture::new(facade, &self.bins, BufferTextureType::Float);
         let buf_tex: BufferTexture<f32> = match buf_tex {
             Ok(t) => t,
             Err(_) => return,
         };
-        let mut target = display.draw();
+        let start = scope::trigger(&self.recent_samples, WAVEFORM_SAMPLES);
+        let waveform = &self.recent_samples[start..start + WAVEFORM_SAMPLES];
+        let wave_tex = BufferTexture::new(facade, waveform, BufferTextureType::Float);
+        let wave_tex: BufferTexture<f32> = match wave_tex {
+            Ok(t) => t,
+            Err(_) => return,
+        };
         target.clear_color(0., 0., 0., 0.);
-        target
+        let drawn = target
             .draw(
-                &vertex_buffer,
+                &self.vertex_buffer,
                 &glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
-                &program,
+                &scene.program,
                 &uniform!{
                             tex: &buf_tex,
-                            time: t,
+                            time: self.time,
+                            waveform: &wave_tex,
+                            beat_age: self.beat_age,
+                            xy_scope: &self.xy_scope,
+                            correlation: self.goniometer.correlation,
+                            resolution: [width as f32, height as f32],
+                            aspect: width as f32 / height as f32,
+                            pixel_ratio: self.pixel_ratio,
+                            // -1 when there is no tone, otherwise 0 (C) up to 12
+                            pitch_class: spec.pitch.as_ref().map(|p| p.pitch_class()).unwrap_or(-1.0),
+                            // tonic of the estimated key, -1 when unknown
+                            key_tonic: spec.key.as_ref().map(|k| k.tonic as f32).unwrap_or(-1.0),
+                            // spectral descriptors, frequencies relative to nyquist
+                            centroid: spec.features.centroid / nyquist,
+                            spread: spec.features.spread / nyquist,
+                            rolloff: spec.features.rolloff / nyquist,
+                            flatness: spec.features.flatness,
+                            zero_crossing_rate: spec.features.zero_crossing_rate,
+                            rms: spec.features.rms,
+                            crest: spec.features.crest,
+                            loudness: spec.features.loudness,
+                            // EBU R128 meter
+                            lufs_momentary: spec.loudness.momentary,
+                            lufs_short_term: spec.loudness.short_term,
+                            lufs_integrated: spec.loudness.integrated,
+                            true_peak: spec.loudness.true_peak,
+                            overlay: self.overlay.visible,
+                            // waterfall
+                            history: &self.history.texture,
+                            newest_row: self.history.newest as i32,
+                            colormap: self.colormap as i32,
+                            log_amplitude: self.amplitude == Amplitude::Log,
+                            // shadertoy
+                            iResolution: [width as f32, height as f32, 1.0],
+                            iTime: self.time,
+                            iTimeDelta: self.time_delta,
+                            iFrame: self.frame_count,
+                            iMouse: self.mouse,
+                            iChannel0: self.audio.texture.sampled()
+                                .wrap_function(SamplerWrapFunction::Clamp),
                         },
                 &Default::default(),
-            )
-            .unwrap();
+            );
+        // e.g. a user shader declaring a uniform with a different type
+        if let Err(e) = drawn {
+            scene.error = Some(format!("{}: {}", scene.name, e));
+        }
+        // the layout of custom shaders is unknown
+        let axis = if scene.custom { None } else { scene.mode.axis() };
+        self.overlay.draw(
+            facade,
+            target,
+            spec,
+            axis,
+            self.gain,
+            self.beat_age,
+            self.pixel_ratio,
+        );
+        if let Some(ref error) = scene.error {
+            let scale = u32::max((ERROR_SCALE as f32 * self.pixel_ratio).round() as u32, 1);
+            let lines = text::wrap(error, text::columns(width, scale));
+            self.text
+                .draw(facade, target, &lines, (0, 0), scale, [1.0, 0.3, 0.3, 1.0]);
+        }
+        self.frame_count += 1;
+    }
+}
+
+fn monitor(events_loop: &glutin::EventsLoop, index: Option<usize>) -> glutin::MonitorId {
+    index
+        .and_then(|i| events_loop.get_available_monitors().nth(i))
+        .unwrap_or_else(|| events_loop.get_primary_monitor())
+}
+
+// a window showing one preset
+struct Window {
+    display: glium::Display,
+    // created along with the first frame
+    renderer: Option<Renderer>,
+    settings: Settings,
+    title: String,
+    paused: bool,
+    fullscreen: bool,
+    // in pixels from the bottom left
+    cursor: (f32, f32),
+    pressed: bool,
+}
+
+impl Window {
+    fn new(events_loop: &glutin::EventsLoop, settings: &Settings, vsync: bool) -> Window {
+        let factor = monitor(events_loop, settings.monitor).get_hidpi_factor();
+        let window = WindowBuilder::new()
+            .with_title("soundvis".to_string())
+            .with_dimensions(
+                (settings.size.0 as f32 * factor) as u32,
+                (settings.size.1 as f32 * factor) as u32,
+            );
+        // swapping the buffers of a vsynced window waits for the next vsync, this paces the
+        // render loop
+        let context = glutin::ContextBuilder::new().with_vsync(vsync);
+        let display = glium::Display::new(window, context, events_loop).unwrap();
+        if settings.fullscreen {
+            display
+                .gl_window()
+                .set_fullscreen(Some(monitor(events_loop, settings.monitor)));
+        }
+        Window {
+            display: display,
+            renderer: None,
+            settings: settings.clone(),
+            title: String::new(),
+            paused: false,
+            fullscreen: settings.fullscreen,
+            cursor: (0.0, 0.0),
+            pressed: false,
+        }
+    }
+
+    fn push(&mut self, frame: SpectrumFrame) {
+        if self.paused {
+            return;
+        }
+        if let Some(ref mut renderer) = self.renderer {
+            renderer.push(frame);
+            return;
+        }
+        self.renderer = Some(Renderer::new(&self.display, &self.settings, frame));
+    }
+
+    fn draw(&mut self) {
+        let mut target = self.display.draw();
+        match self.renderer {
+            Some(ref mut renderer) => {
+                if self.title != renderer.scene_name() {
+                    self.title = renderer.scene_name().to_string();
+                    self.display
+                        .gl_window()
+                        .set_title(&format!("soundvis - {}", self.title));
+                }
+                renderer.pixel_ratio = self.display.gl_window().hidpi_factor();
+                renderer.draw(&self.display, &mut target);
+            }
+            // nothing to show until the first frame arrives
+            None => target.clear_color(0., 0., 0., 0.),
+        }
         target.finish().unwrap();
-        events_loop.poll_events(|event| match event {
-            glutin::Event::WindowEvent { event, .. } => {
-                match event {
-                    glutin::WindowEvent::Closed => return,
-                    _ => (),
+    }
+
+    fn handle(
+        &mut self,
+        event: glutin::WindowEvent,
+        events_loop: &glutin::EventsLoop,
+        settings: &Settings,
+    ) -> Option<Change> {
+        use glium::glutin::VirtualKeyCode;
+
+        // the window itself can be handled before the first frame
+        match event {
+            glutin::WindowEvent::Resized(width, height) => {
+                self.display.gl_window().resize(width, height);
+                return None;
+            }
+            glutin::WindowEvent::KeyboardInput {
+                input: glutin::KeyboardInput {
+                    state: glutin::ElementState::Pressed,
+                    virtual_keycode: Some(key),
+                    ..
+                },
+                ..
+            } if key == VirtualKeyCode::F || key == VirtualKeyCode::Escape =>
+            {
+                self.fullscreen = key == VirtualKeyCode::F && !self.fullscreen;
+                let target = if self.fullscreen {
+                    Some(monitor(events_loop, settings.monitor))
+                } else {
+                    None
+                };
+                self.display.gl_window().set_fullscreen(target);
+                return None;
+            }
+            _ => (),
+        }
+
+        let (_, height) = self.display.get_framebuffer_dimensions();
+        let renderer = match self.renderer {
+            Some(ref mut renderer) => renderer,
+            None => return None,
+        };
+        match event {
+            glutin::WindowEvent::CursorMoved { position: (x, y), .. } => {
+                self.cursor = (x as f32, height as f32 - y as f32);
+                if self.pressed {
+                    renderer.mouse[0] = self.cursor.0;
+                    renderer.mouse[1] = self.cursor.1;
+                }
+            }
+            glutin::WindowEvent::MouseInput {
+                state,
+                button: glutin::MouseButton::Left,
+                ..
+            } => {
+                self.pressed = state == glutin::ElementState::Pressed;
+                let mouse = &mut renderer.mouse;
+                if self.pressed {
+                    *mouse = [self.cursor.0, self.cursor.1, self.cursor.0, self.cursor.1];
+                } else {
+                    mouse[2] = -mouse[2].abs();
+                    mouse[3] = -mouse[3].abs();
                 }
             }
+            glutin::WindowEvent::KeyboardInput {
+                input: glutin::KeyboardInput {
+                    state: glutin::ElementState::Pressed,
+                    virtual_keycode: Some(key),
+                    ..
+                },
+                ..
+            } => match key {
+                VirtualKeyCode::Right | VirtualKeyCode::Left => {
+                    renderer.switch(if key == VirtualKeyCode::Right { 1 } else { -1 });
+                    let mode = renderer.scenes[renderer.current].mode;
+                    return Some(Change::Preset(mode));
+                }
+                VirtualKeyCode::A => {
+                    renderer.toggle_rotation();
+                    return Some(Change::Rotate(renderer.rotate));
+                }
+                VirtualKeyCode::O => {
+                    renderer.toggle_overlay();
+                    return Some(Change::Overlay(renderer.overlay.visible));
+                }
+                VirtualKeyCode::Space => self.paused = !self.paused,
+                VirtualKeyCode::Up | VirtualKeyCode::Down => {
+                    if key == VirtualKeyCode::Up {
+                        renderer.gain *= GAIN_STEP;
+                    } else {
+                        renderer.gain /= GAIN_STEP;
+                    }
+                    return Some(Change::Gain(renderer.gain));
+                }
+                _ => (),
+            },
             _ => (),
+        }
+        None
+    }
+}
+
+// open a window for every mode, they share the frames of `spec_rx` and the cues of `show`
+pub fn visual(
+    settings: Settings,
+    modes: Vec<Mode>,
+    show: Option<Show>,
+    mut control: Watcher,
+    mut spec_rx: broadcast::Receiver<SpectrumFrame>,
+) {
+    let mut events_loop = glutin::EventsLoop::new();
+    let mut pts = None;
+    // the windows show nothing until the first frame arrives
+    let mut started = false;
+    let mut cues = show.map(CuePlayer::new);
+    // only the first window waits for vsync, otherwise every window would wait in turn and
+    // divide the frame rate by their number, once it is closed MAX_FPS paces the others
+    let mut windows: Vec<Window> = modes
+        .into_iter()
+        .enumerate()
+        .map(|(i, mode)| {
+            let settings = Settings {
+                mode: mode,
+                ..settings.clone()
+            };
+            Window::new(&events_loop, &settings, i == 0)
+        })
+        .collect();
+
+    let min_interval = time::Duration::from_secs(1) / MAX_FPS;
+    let mut last_tick = time::Instant::now();
+    let mut connected = true;
+    while !windows.is_empty() {
+        let elapsed = last_tick.elapsed();
+        if elapsed < min_interval {
+            sleep(min_interval - elapsed);
+        }
+
+        let mut events = vec![];
+        events_loop.poll_events(|event| if let glutin::Event::WindowEvent { window_id, event } =
+            event
+        {
+            events.push((window_id, event));
         });
-        spec = spec_rx.recv().unwrap();
+        for (id, event) in events {
+            let index = match windows.iter().position(|w| w.display.gl_window().id() == id) {
+                Some(index) => index,
+                None => continue,
+            };
+            match event {
+                glutin::WindowEvent::Closed => {
+                    windows.remove(index);
+                }
+                event => {
+                    // keep the parameters of the control API in step with the keys
+                    if let Some(change) = windows[index].handle(event, &events_loop, &settings) {
+                        control.note(&change);
+                    }
+                }
+            }
+        }
+
+        // the latest frame if there is a new one, keep receiving while paused so the frames
+        // do not pile up and keep drawing once the audio is gone
+        if connected {
+            match spec_rx.try_recv() {
+                Ok(next) => {
+                    pts = next.pts;
+                    started = true;
+                    for window in windows.iter_mut() {
+                        window.push(next.clone());
+                    }
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => connected = false,
+            }
+        }
+
+        // the changes wait until there are renderers to apply them to
+        let mut changes = vec![];
+        if started {
+            changes = control.changes();
+            if let Some(ref mut cues) = cues {
+                for change in cues.advance(pts) {
+                    control.note(&change);
+                    changes.push(change);
+                }
+            }
+        }
+        for change in changes {
+            for window in windows.iter_mut() {
+                if let Some(ref mut renderer) = window.renderer {
+                    renderer.apply(&change);
+                }
+            }
+        }
+
+        let elapsed = last_tick.elapsed();
+        last_tick = time::Instant::now();
+        let dt = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1000000000.0;
+        for window in windows.iter_mut() {
+            if let Some(ref mut renderer) = window.renderer {
+                if !window.paused {
+                    renderer.advance(dt);
+                }
+            }
+            window.draw();
+        }
     }
 }
@@ -0,0 +1,409 @@
+// Streams spectrum frames to browsers via WebSocket.
+//
+// Clients connect to `ws://<address>/stream` and may pick the decimation with query
+// parameters, e.g. `/stream?fps=30&bands=24&format=binary`. Every other path serves a small
+// demo page.
+//
+// JSON messages look like `{"sequence":1,"pts":123,"beat":false,"rms":0.1,"peak":0.2,"bands":[...]}`
+// plus a `pitch` object (frequency, note, name, cents, confidence) or null, the 12 element
+// `chroma` vector, `key`/`chord` objects (name, confidence) or null and the spectral
+// descriptors in `features` and the EBU R128 values in `loudness`.
+// Binary messages are little endian: u64 sequence, u8 beat, f32 rms, f32 peak, u16 band
+// count followed by one f32 per band, then the pitch as f32 frequency and f32 confidence
+// (both 0 without a pitch) and the key as u8 tonic (255 without a key), u8 mode (0 major,
+// 1 minor) and f32 confidence. The chroma, chord, features and loudness are only sent as
+// JSON.
+//
+// `beat` is set when a beat was detected since the previous message, so clients limited to
+// a lower frame rate still get every beat.
+//
+// Pings of the clients are answered and a close frame is echoed before the connection is
+// shut down, anything else the clients send is ignored.
+
+use std::cmp;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::spawn;
+
+use base64;
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure::Error;
+use serde_json;
+use sha1;
+
+use bands;
+use broadcast;
+use frame::SpectrumFrame;
+use chroma::{self, Chord, Key};
+use features::Features;
+use loudness::Loudness;
+use pitch::Pitch;
+
+const DEMO_PAGE: &str = include_str!("../static/index.html");
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// upper bound of the payload of a frame sent by a client, they only send control frames
+const MAX_CLIENT_PAYLOAD: u64 = 64 * 1024;
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Format {
+    Json,
+    Binary,
+}
+
+struct Options {
+    fps: Option<u32>,
+    bands: Option<usize>,
+    format: Format,
+}
+
+impl Options {
+    fn from_query(query: &str) -> Options {
+        let mut options = Options {
+            fps: Some(30),
+            bands: None,
+            format: Format::Json,
+        };
+        for pair in query.split('&') {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("fps"), Some(v)) => {
+                    options.fps = v.parse().ok().and_then(|fps| if fps == 0 { None } else { Some(fps) })
+                }
+                (Some("bands"), Some(v)) => options.bands = v.parse().ok(),
+                (Some("format"), Some("binary")) => options.format = Format::Binary,
+                (Some("format"), Some("json")) => options.format = Format::Json,
+                _ => (),
+            }
+        }
+        options
+    }
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    sequence: u64,
+    pts: Option<u64>,
+    beat: bool,
+    rms: f32,
+    peak: f32,
+    bands: &'a [f32],
+    pitch: Option<NamedMessage<'a, Pitch>>,
+    chroma: &'a [f32],
+    key: Option<NamedMessage<'a, Key>>,
+    chord: Option<NamedMessage<'a, Chord>>,
+    features: &'a Features,
+    loudness: &'a Loudness,
+}
+
+// adds the human readable name to the serialized fields of `inner`
+#[derive(Serialize)]
+struct NamedMessage<'a, T: 'a> {
+    #[serde(flatten)]
+    inner: &'a T,
+    name: String,
+}
+
+struct Request {
+    path: String,
+    query: String,
+    websocket_key: Option<String>,
+}
+
+fn read_request(stream: &TcpStream) -> Result<Request, Error> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    // GET /path?query HTTP/1.1
+    let target = line.split_whitespace().nth(1).unwrap_or("/").to_string();
+    let mut target = target.splitn(2, '?');
+    let path = target.next().unwrap_or("/").to_string();
+    let query = target.next().unwrap_or("").to_string();
+
+    let mut websocket_key = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let mut header = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (header.next(), header.next()) {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                websocket_key = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    Ok(Request {
+        path: path,
+        query: query,
+        websocket_key: websocket_key,
+    })
+}
+
+fn accept_key(key: &str) -> String {
+    let digest = sha1::Sha1::from(format!("{}{}", key, WEBSOCKET_GUID)).digest();
+    base64::encode(&digest.bytes())
+}
+
+// write a single unmasked, unfragmented websocket frame
+fn write_frame(stream: &mut TcpStream, opcode: u8, payload: &[u8]) -> Result<(), Error> {
+    let mut header = vec![0x80 | opcode];
+    if payload.len() < 126 {
+        header.push(payload.len() as u8);
+    } else if payload.len() <= 0xffff {
+        header.push(126);
+        header.extend(&[(payload.len() >> 8) as u8, payload.len() as u8]);
+    } else {
+        header.push(127);
+        header.extend((0..8).rev().map(|i| (payload.len() as u64 >> (i * 8)) as u8));
+    }
+    stream.write_all(&header)?;
+    stream.write_all(payload)?;
+    Ok(())
+}
+
+// read a frame sent by a client, their payload is masked
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let opcode = reader.read_u8()? & 0x0f;
+    let second = reader.read_u8()?;
+    let length = match second & 0x7f {
+        126 => reader.read_u16::<BigEndian>()? as u64,
+        127 => reader.read_u64::<BigEndian>()?,
+        length => length as u64,
+    };
+    if length > MAX_CLIENT_PAYLOAD {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut mask = [0; 4];
+    if second & 0x80 != 0 {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, v) in payload.iter_mut().enumerate() {
+        *v ^= mask[i % 4];
+    }
+    Ok((opcode, payload))
+}
+
+// answer the control frames of a client until it closes the connection
+fn read_control_frames(mut reader: TcpStream, writer: &Mutex<TcpStream>, closed: &AtomicBool) {
+    loop {
+        match read_frame(&mut reader) {
+            Ok((OPCODE_PING, payload)) => {
+                if write_frame(&mut writer.lock().unwrap(), OPCODE_PONG, &payload).is_err() {
+                    break;
+                }
+            }
+            Ok((OPCODE_CLOSE, payload)) => {
+                // echo the status code
+                let length = cmp::min(payload.len(), 2);
+                let _ = write_frame(&mut writer.lock().unwrap(), OPCODE_CLOSE, &payload[..length]);
+                break;
+            }
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
+    closed.store(true, Ordering::SeqCst);
+    let _ = reader.shutdown(Shutdown::Both);
+}
+
+fn encode_binary(frame: &SpectrumFrame, bands: &[f32]) -> Vec<u8> {
+    let mut wrt = vec![];
+    wrt.write_u64::<LittleEndian>(frame.sequence).unwrap();
+    wrt.write_u8(frame.beat as u8).unwrap();
+    wrt.write_f32::<LittleEndian>(frame.rms).unwrap();
+    wrt.write_f32::<LittleEndian>(frame.peak).unwrap();
+    wrt.write_u16::<LittleEndian>(bands.len() as u16).unwrap();
+    for v in bands {
+        wrt.write_f32::<LittleEndian>(*v).unwrap();
+    }
+    match frame.pitch {
+        Some(ref pitch) => {
+            wrt.write_f32::<LittleEndian>(pitch.frequency).unwrap();
+            wrt.write_f32::<LittleEndian>(pitch.confidence).unwrap();
+        }
+        None => {
+            wrt.write_f32::<LittleEndian>(0.0).unwrap();
+            wrt.write_f32::<LittleEndian>(0.0).unwrap();
+        }
+    }
+    match frame.key {
+        Some(ref key) => {
+            wrt.write_u8(key.tonic).unwrap();
+            wrt.write_u8(match key.mode {
+                chroma::Mode::Major => 0,
+                chroma::Mode::Minor => 1,
+            }).unwrap();
+            wrt.write_f32::<LittleEndian>(key.confidence).unwrap();
+        }
+        None => {
+            wrt.write_u8(255).unwrap();
+            wrt.write_u8(0).unwrap();
+            wrt.write_f32::<LittleEndian>(0.0).unwrap();
+        }
+    }
+    wrt
+}
+
+fn stream_frames(
+    stream: &Mutex<TcpStream>,
+    closed: &AtomicBool,
+    options: Options,
+    mut rx: broadcast::Receiver<SpectrumFrame>,
+) -> Result<(), Error> {
+    while let Ok(frame) = rx.recv() {
+        if closed.load(Ordering::SeqCst) {
+            break;
+        }
+        let bands = match options.bands {
+            Some(n) => bands::group(&frame.bins, n),
+            None => frame.bins.clone(),
+        };
+        match options.format {
+            Format::Json => {
+                let message = serde_json::to_string(&Message {
+                    sequence: frame.sequence,
+                    pts: frame.pts,
+                    beat: frame.beat,
+                    rms: frame.rms,
+                    peak: frame.peak,
+                    bands: &bands,
+                    pitch: frame.pitch.as_ref().map(|pitch| {
+                        NamedMessage {
+                            inner: pitch,
+                            name: pitch.name(),
+                        }
+                    }),
+                    chroma: &frame.chroma,
+                    key: frame.key.as_ref().map(|key| {
+                        NamedMessage {
+                            inner: key,
+                            name: key.name(),
+                        }
+                    }),
+                    chord: frame.chord.as_ref().map(|chord| {
+                        NamedMessage {
+                            inner: chord,
+                            name: chord.name(),
+                        }
+                    }),
+                    features: &frame.features,
+                    loudness: &frame.loudness,
+                })?;
+                write_frame(&mut stream.lock().unwrap(), OPCODE_TEXT, message.as_bytes())?;
+            }
+            Format::Binary => {
+                let message = encode_binary(&frame, &bands);
+                write_frame(&mut stream.lock().unwrap(), OPCODE_BINARY, &message)?
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle(
+    mut stream: TcpStream,
+    spectrum_tx: broadcast::Sender<SpectrumFrame>,
+) -> Result<(), Error> {
+    let request = read_request(&stream)?;
+
+    match (request.path.as_str(), request.websocket_key) {
+        ("/stream", Some(key)) => {
+            write!(
+                stream,
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(&key)
+            )?;
+            let options = Options::from_query(&request.query);
+            let name = format!("websocket {}", stream.peer_addr()?);
+            let rx = spectrum_tx.subscribe(&name, options.fps);
+            drop(spectrum_tx);
+
+            let reader = stream.try_clone()?;
+            let stream = Arc::new(Mutex::new(stream));
+            let closed = Arc::new(AtomicBool::new(false));
+            {
+                let stream = Arc::clone(&stream);
+                let closed = Arc::clone(&closed);
+                spawn(move || read_control_frames(reader, &stream, &closed));
+            }
+            let result = stream_frames(&stream, &closed, options, rx);
+            // stops the reader as well
+            let _ = stream.lock().unwrap().shutdown(Shutdown::Both);
+            result
+        }
+        _ => {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/html; charset=utf-8\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                DEMO_PAGE.len(),
+                DEMO_PAGE
+            )?;
+            Ok(())
+        }
+    }
+}
+
+pub fn serve(address: String, spectrum_tx: broadcast::Sender<SpectrumFrame>) {
+    let listener = match TcpListener::bind(&address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Failed to listen for websocket clients on {}: {}", address, e);
+            return;
+        }
+    };
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let spectrum_tx = spectrum_tx.clone();
+        spawn(move || if let Err(e) = handle(stream, spectrum_tx) {
+            println!("websocket client disconnected: {}", e);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept_key, read_frame};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_accept_key() {
+        // example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_read_masked_frame() {
+        // masked "Hello" from RFC 6455
+        let mut frame = Cursor::new(vec![
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ]);
+        let (opcode, payload) = read_frame(&mut frame).unwrap();
+        assert_eq!(opcode, 0x1);
+        assert_eq!(payload, b"Hello".to_vec());
+    }
+}
@@ -0,0 +1,21 @@
+// keep the most recent samples of a stream in `window`, in chronological order
+pub fn slide(window: &mut [f32], samples: &[f32]) {
+    let new = usize::min(samples.len(), window.len());
+    window.rotate_left(new);
+    let offset = window.len() - new;
+    window[offset..].copy_from_slice(&samples[samples.len() - new..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::slide;
+
+    #[test]
+    fn test_slide() {
+        let mut window = vec![0.0; 4];
+        slide(&mut window, &[1.0, 2.0]);
+        assert_eq!(window, vec![0.0, 0.0, 1.0, 2.0]);
+        slide(&mut window, &[3.0, 4.0, 5.0, 6.0, 7.0]);
+        assert_eq!(window, vec![4.0, 5.0, 6.0, 7.0]);
+    }
+}